@@ -1,8 +1,15 @@
-use config::{Config, ConfigError, File, Value, ValueKind};
+use config::{ConfigError, Map, Value, ValueKind};
+use indexmap::IndexMap;
 use std::collections::{HashMap, HashSet};
 use std::fmt;
 
+use crate::diff::{diff, KeyDiff};
 use crate::error::{ConfpilerError, Result};
+use crate::interpolate::{interpolate, Interpolation};
+use crate::keys::{is_valid_env_name, sanitize_env_name, InvalidKeyPolicy, KeyFormat};
+use crate::provenance::{Provenance, ValueType};
+use crate::schema::Schema;
+use crate::source::{check_environments, Layer, Source, SourceFormat};
 
 /// A representation of a flattened, compiled configuration.
 ///
@@ -51,11 +58,22 @@ use crate::error::{ConfpilerError, Result};
 /// "FOO__BAZ": "true"
 /// "HOOF": "doof"
 /// ```
+///
+/// Keys are kept in the order they first appear in the configs, with
+/// overridden keys keeping their original position.
+///
+/// Additionally, the [Provenance] of every key is tracked, so it is possible
+/// to determine that `FOO__BAZ` was set by `foo/production`, after previously
+/// being set by `foo/default`.
 #[derive(Debug, Clone, Default, Eq, PartialEq)]
 pub struct FlatConfig {
     origin: String,
 
-    items: HashMap<String, String>,
+    layers: Vec<String>,
+
+    items: IndexMap<String, String>,
+
+    provenance: HashMap<String, Provenance>,
 }
 
 impl FlatConfig {
@@ -72,13 +90,206 @@ impl FlatConfig {
         FlatConfigBuilder::default()
     }
 
+    /// Create a config from keys and values that are already flattened, such
+    /// as the saved output of a previous build.
+    ///
+    /// The keys and values are used as-is, with every key attributed to the
+    /// given origin.
+    ///
+    /// # Examples
+    /// ```
+    /// use confpiler::{indexmap::IndexMap, FlatConfig};
+    ///
+    /// let items = IndexMap::from([("foo.bar".to_string(), "10".to_string())]);
+    /// let conf = FlatConfig::from_items("saved.json", items.clone());
+    ///
+    /// assert_eq!(conf.items(), &items);
+    /// assert_eq!(conf.origin_of("foo.bar"), Some("saved.json"));
+    /// ```
+    pub fn from_items(origin: &str, items: IndexMap<String, String>) -> Self {
+        let provenance = items
+            .iter()
+            .map(|(k, v)| (k.clone(), Provenance::new(origin, v, ValueType::infer(v))))
+            .collect();
+
+        Self {
+            origin: origin.to_string(),
+            layers: vec![origin.to_string()],
+            items,
+            provenance,
+        }
+    }
+
     /// Convenience method for getting reference to the internal key/value map.
-    pub fn items(&self) -> &HashMap<String, String> {
+    ///
+    /// Iterating over this map yields the keys in the order they first appeared
+    /// in the merged configs. The map type is re-exported as
+    /// [confpiler::indexmap](crate::indexmap), and can be serialized via
+    /// `serde`.
+    ///
+    /// # Examples
+    /// ```
+    /// use confpiler::{indexmap::IndexMap, FlatConfig};
+    ///
+    /// let conf = FlatConfig::default();
+    /// let items: &IndexMap<String, String> = conf.items();
+    ///
+    /// assert!(items.is_empty());
+    /// ```
+    pub fn items(&self) -> &IndexMap<String, String> {
         &self.items
     }
 
+    /// Get the origins of the configs that were merged to produce this config,
+    /// in the order they were merged.
+    ///
+    /// This includes the default and environment configs that each directory
+    /// was resolved into.
+    ///
+    /// # Examples
+    /// ```
+    /// use confpiler::FlatConfig;
+    ///
+    /// let conf = FlatConfig::default();
+    ///
+    /// assert!(conf.layers().is_empty());
+    /// ```
+    pub fn layers(&self) -> &[String] {
+        &self.layers
+    }
+
+    /// Get the [Provenance] for the given key, if the key exists.
+    ///
+    /// # Examples
+    /// ```
+    /// use confpiler::FlatConfig;
+    ///
+    /// let conf = FlatConfig::default();
+    ///
+    /// assert!(conf.provenance("FOO__BAR").is_none());
+    /// ```
+    pub fn provenance(&self, key: &str) -> Option<&Provenance> {
+        self.provenance.get(key)
+    }
+
+    /// Get the origin of the config that set the final value for the given
+    /// key, if the key exists.
+    ///
+    /// # Examples
+    /// ```
+    /// use confpiler::FlatConfig;
+    ///
+    /// let conf = FlatConfig::default();
+    ///
+    /// assert!(conf.origin_of("FOO__BAR").is_none());
+    /// ```
+    pub fn origin_of(&self, key: &str) -> Option<&str> {
+        self.provenance
+            .get(key)
+            .filter(|p| !p.is_deleted())
+            .map(|p| p.origin())
+    }
+
+    /// Iterate over the keys that were deleted by a null in a later config
+    /// (and not set again by any config after that).
+    ///
+    /// The [Provenance] of these keys remains available via
+    /// [provenance](FlatConfig::provenance).
+    ///
+    /// # Examples
+    /// ```
+    /// use confpiler::FlatConfig;
+    ///
+    /// let conf = FlatConfig::default();
+    ///
+    /// assert_eq!(conf.deleted_keys().count(), 0);
+    /// ```
+    pub fn deleted_keys(&self) -> impl Iterator<Item = &str> {
+        self.provenance
+            .iter()
+            .filter(|(_, p)| p.is_deleted())
+            .map(|(k, _)| k.as_str())
+    }
+
+    /// Compare `self` to `other`, treating `self` as the old config and
+    /// `other` as the new one.
+    ///
+    /// Removed and changed keys are listed in the order they appear in
+    /// `self`, followed by added keys in the order they appear in `other`.
+    /// Keys with the same value in both are not listed, even if they were set
+    /// by different configs.
+    ///
+    /// # Examples
+    /// ```
+    /// use confpiler::{FlatConfig, KeyDiff, SourceFormat};
+    /// # use confpiler::error::ConfpilerError;
+    /// # fn main() -> Result<(), ConfpilerError> {
+    ///
+    /// let (staging, _) = FlatConfig::builder()
+    ///     .add_config_str("staging", "FOO=1\nBAR=2\n", SourceFormat::Dotenv)
+    ///     .build()?;
+    /// let (production, _) = FlatConfig::builder()
+    ///     .add_config_str("production", "FOO=1\nBAR=3\n", SourceFormat::Dotenv)
+    ///     .build()?;
+    ///
+    /// assert_eq!(
+    ///     staging.diff(&production),
+    ///     vec![KeyDiff::Changed {
+    ///         key: "BAR".to_string(),
+    ///         old_value: "2".to_string(),
+    ///         new_value: "3".to_string(),
+    ///         old_origin: Some("staging".to_string()),
+    ///         new_origin: Some("production".to_string()),
+    ///     }]
+    /// );
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn diff(&self, other: &Self) -> Vec<KeyDiff> {
+        diff(self, other)
+    }
+
+    /// Delete the given key, along with any keys nested beneath it, returning
+    /// `false` if there was nothing to delete.
+    fn delete(&mut self, key: &str, origin: &str, separator: &str) -> bool {
+        let nested = self
+            .items
+            .keys()
+            .filter(|k| {
+                k.strip_prefix(key)
+                    .map_or(false, |rest| rest.is_empty() || rest.starts_with(separator))
+            })
+            .cloned()
+            .collect::<Vec<_>>();
+
+        self.delete_keys(nested.iter(), origin)
+    }
+
+    /// Delete exactly the given keys, returning `false` if none of them
+    /// existed.
+    fn delete_keys<'k, I>(&mut self, keys: I, origin: &str) -> bool
+    where
+        I: IntoIterator<Item = &'k String>,
+    {
+        let mut deleted = false;
+
+        for k in keys {
+            if self.items.shift_remove(k).is_some() {
+                deleted = true;
+                if let Some(p) = self.provenance.get_mut(k) {
+                    p.delete(origin);
+                }
+            }
+        }
+
+        deleted
+    }
+
     /// Merge another [FlatConfig] into `self`.
     ///
+    /// The [Provenance] of every key in `other` is appended to the provenance
+    /// of the corresponding key in `self`, even if the value was unchanged.
+    ///
     /// See [MergeWarning] for the kinds of warnings returned by this function
     /// and when/why they are generated.
     ///
@@ -96,10 +307,32 @@ impl FlatConfig {
     pub fn merge(&mut self, other: &Self) -> Vec<MergeWarning> {
         let mut warnings = Vec::new();
 
+        self.layers.extend(other.layers.iter().cloned());
+
         for (k, v) in other.items.iter() {
+            let value_type = other
+                .provenance
+                .get(k)
+                .map(|p| p.value_type())
+                .unwrap_or(ValueType::String);
+
             self.items
                 .entry(k.to_string())
                 .and_modify(|e| {
+                    if let Some(p) = self
+                        .provenance
+                        .get(k)
+                        .filter(|p| !p.value_type().is_compatible_with(value_type))
+                    {
+                        warnings.push(MergeWarning::TypeChanged {
+                            origin: p.origin().to_string(),
+                            overrider: other.origin.clone(),
+                            key: k.to_string(),
+                            from: p.value_type(),
+                            to: value_type,
+                        });
+                    }
+
                     if e == v {
                         warnings.push(MergeWarning::RedundantValue {
                             overrider: other.origin.clone(),
@@ -111,10 +344,44 @@ impl FlatConfig {
                     }
                 })
                 .or_insert_with(|| v.to_string());
+
+            let history = other
+                .provenance
+                .get(k)
+                .cloned()
+                .unwrap_or_else(|| Provenance::new(&other.origin, v, value_type));
+
+            self.provenance
+                .entry(k.to_string())
+                .and_modify(|e| e.extend(&history))
+                .or_insert(history);
         }
 
         warnings
     }
+
+    /// Get a [MergeWarning::ShadowedOverride] for every value set by one of
+    /// the given environment-specific configs that merging `other` would
+    /// replace.
+    fn shadowed_by(&self, other: &Self, environment_layers: &HashSet<&str>) -> Vec<MergeWarning> {
+        other
+            .items
+            .iter()
+            .filter_map(|(k, v)| {
+                let current = self.items.get(k).filter(|e| *e != v)?;
+                let origin = self
+                    .origin_of(k)
+                    .filter(|o| environment_layers.contains(o))?;
+
+                Some(MergeWarning::ShadowedOverride {
+                    origin: origin.to_string(),
+                    overrider: other.origin.clone(),
+                    key: k.to_string(),
+                    value: current.clone(),
+                })
+            })
+            .collect()
+    }
 }
 
 /// This is the builder for [FlatConfig].
@@ -139,26 +406,46 @@ impl FlatConfig {
 #[derive(Debug, Clone, Eq, PartialEq)]
 pub struct FlatConfigBuilder {
     prefix: Option<String>,
-    configs: Vec<String>,
+    configs: Vec<Source>,
+    default_basename: String,
+    environments: Vec<String>,
+    require_environments: bool,
+    key_format: KeyFormat,
     separator: String,
     array_separator: String,
+    array_strategy: ArrayStrategy,
+    index_separator: Option<String>,
+    array_length_suffix: Option<String>,
+    json_arrays: bool,
+    json_keys: Vec<String>,
+    new_key_policy: NewKeyPolicy,
+    type_change_policy: TypeChangePolicy,
+    invalid_key_policy: InvalidKeyPolicy,
+    interpolation: Interpolation,
+    null_deletes: bool,
+    required_marker: Option<String>,
+    schema: Option<Schema>,
 }
 
 impl FlatConfigBuilder {
     pub const DEFAULT_SEPARATOR: &'static str = "__";
     pub const DEFAULT_ARRAY_SEPARATOR: &'static str = ",";
+    pub const DEFAULT_BASENAME: &'static str = "default";
 
     /// Adds the given config path to the list of configs.
     ///
     ///
     /// * Ordering is important here, as values in the last added config will
-    /// overwrite those in the previously added configs.
+    ///   overwrite those in the previously added configs.
     /// * Actual loading of the specified config files does not happen until
-    /// [build()](FlatConfigBuilder::build) is invoked.
+    ///   [build()](FlatConfigBuilder::build) is invoked.
     /// * The supported config names are the same as supported by the `config-rs`
+    ///   crate.
+    /// * Dotenv files (`.env`, `.env.local`, `legacy.env`, etc.) are also
+    ///   supported. Their keys are considered to already be flattened, so they
+    ///   are used as-is, without a prefix or any formatting.
     /// * Specifying the same config twice will result in an error when
-    /// [build()](FlatConfigBuilder::build) is invoked.
-    /// crate.
+    ///   [build()](FlatConfigBuilder::build) is invoked.
     ///
     /// # Examples
     /// ```
@@ -167,7 +454,173 @@ impl FlatConfigBuilder {
     /// builder.add_config("foo/default");
     /// ```
     pub fn add_config(&mut self, config: &str) -> &mut Self {
-        self.configs.push(config.to_string());
+        self.configs.push(Source::Path(config.to_string()));
+        self
+    }
+
+    /// Adds the given string, in the given format, to the list of configs.
+    ///
+    /// This behaves exactly like [add_config](FlatConfigBuilder::add_config),
+    /// except that nothing is read from disk. The `origin` is used in place of
+    /// a path to refer to the config in warnings, errors, and provenance, and
+    /// must be unique among all added configs.
+    ///
+    /// # Examples
+    /// ```
+    /// use confpiler::{FlatConfig, SourceFormat};
+    /// # use confpiler::error::ConfpilerError;
+    /// # fn main() -> Result<(), ConfpilerError> {
+    ///
+    /// let (conf, warnings) = FlatConfig::builder()
+    ///     .add_config_str("defaults", "foo:\n  bar: 10\n", SourceFormat::Yaml)
+    ///     .add_config_str("overrides", "[foo]\nbar = 11\n", SourceFormat::Toml)
+    ///     .build()?;
+    ///
+    /// assert_eq!(conf.items().get("FOO__BAR"), Some(&"11".to_string()));
+    /// assert!(warnings.is_empty());
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn add_config_str(
+        &mut self,
+        origin: &str,
+        content: &str,
+        format: SourceFormat,
+    ) -> &mut Self {
+        self.configs.push(Source::Str {
+            origin: origin.to_string(),
+            content: content.to_string(),
+            format,
+        });
+        self
+    }
+
+    /// Adds the given, already-parsed, nested value to the list of configs.
+    ///
+    /// This behaves exactly like [add_config](FlatConfigBuilder::add_config),
+    /// except that nothing is read from disk. The `origin` is used in place of
+    /// a path to refer to the config in warnings, errors, and provenance, and
+    /// must be unique among all added configs.
+    ///
+    /// # Examples
+    /// ```
+    /// use confpiler::FlatConfig;
+    /// # use confpiler::error::ConfpilerError;
+    /// # fn main() -> Result<(), ConfpilerError> {
+    ///
+    /// let value = serde_json::json!({"foo": {"bar": 10}});
+    ///
+    /// let (conf, _) = FlatConfig::builder()
+    ///     .add_config_value("generated", value.as_object().unwrap().clone())
+    ///     .build()?;
+    ///
+    /// assert_eq!(conf.items().get("FOO__BAR"), Some(&"10".to_string()));
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn add_config_value(
+        &mut self,
+        origin: &str,
+        value: serde_json::Map<String, serde_json::Value>,
+    ) -> &mut Self {
+        self.configs.push(Source::Value {
+            origin: origin.to_string(),
+            value,
+        });
+        self
+    }
+
+    /// Adds the given directory to the list of configs.
+    ///
+    /// When [build()](FlatConfigBuilder::build) is invoked, the directory is
+    /// replaced by its default config (see
+    /// [with_default_basename](FlatConfigBuilder::with_default_basename)),
+    /// followed by the config for each environment specified via
+    /// [with_environment](FlatConfigBuilder::with_environment), in the order
+    /// they were specified.
+    ///
+    /// * Both the default and the environments may refer to a file by its full
+    ///   name (`production.yaml`) or by its stem (`production`).
+    /// * The default config is required, but environments without a
+    ///   corresponding config are ignored (see
+    ///   [with_require_environments](FlatConfigBuilder::with_require_environments)).
+    /// * Environments may declare a parent in an `environments` config in the
+    ///   directory (like `production-eu: { parent: production }`), in which
+    ///   case the configs for their ancestors are loaded before them.
+    /// * Each directory is processed completely before moving on to the next
+    ///   config, so configs added after a directory always take precedence
+    ///   over every config in that directory.
+    ///
+    /// # Examples
+    /// ```
+    /// use confpiler::FlatConfig;
+    /// let mut builder = FlatConfig::builder();
+    /// builder.add_directory("foo");
+    /// builder.with_environment("production");
+    ///
+    /// // this is equivalent to
+    /// let mut builder = FlatConfig::builder();
+    /// builder.add_config("foo/default");
+    /// builder.add_config("foo/production");
+    /// ```
+    pub fn add_directory(&mut self, directory: &str) -> &mut Self {
+        self.configs.push(Source::Directory(directory.to_string()));
+        self
+    }
+
+    /// Specifies the basename of the default config in every directory added
+    /// via [add_directory](FlatConfigBuilder::add_directory).
+    ///
+    /// The default is `default`.
+    ///
+    /// # Examples
+    /// ```
+    /// use confpiler::FlatConfig;
+    /// let mut builder = FlatConfig::builder();
+    /// builder.with_default_basename("base"); // foo/base.yaml, etc.
+    /// ```
+    pub fn with_default_basename(&mut self, basename: &str) -> &mut Self {
+        self.default_basename = basename.to_string();
+        self
+    }
+
+    /// Adds an environment to load from every directory added via
+    /// [add_directory](FlatConfigBuilder::add_directory).
+    ///
+    /// This may be specified multiple times, with later environments taking
+    /// precedence over earlier ones.
+    ///
+    /// # Examples
+    /// ```
+    /// use confpiler::FlatConfig;
+    /// let mut builder = FlatConfig::builder();
+    /// builder.with_environment("production");
+    /// builder.with_environment("production-us-east-1");
+    /// ```
+    pub fn with_environment(&mut self, environment: &str) -> &mut Self {
+        self.environments.push(environment.to_string());
+        self
+    }
+
+    /// Specifies whether every environment specified via
+    /// [with_environment](FlatConfigBuilder::with_environment) must have a
+    /// config in at least one directory.
+    ///
+    /// This is disabled by default, in which case environments without a
+    /// config are ignored. When enabled, such an environment causes
+    /// [build](FlatConfigBuilder::build) to fail with
+    /// [ConfpilerError::UnknownEnvironment], which lists the environments that
+    /// are available and suggests the closest match. This has no effect if no
+    /// directories were added.
+    ///
+    /// # Examples
+    /// ```
+    /// use confpiler::FlatConfig;
+    /// let mut builder = FlatConfig::builder();
+    /// builder.with_require_environments(true);
+    /// ```
+    pub fn with_require_environments(&mut self, require: bool) -> &mut Self {
+        self.require_environments = require;
         self
     }
 
@@ -187,6 +640,22 @@ impl FlatConfigBuilder {
         self
     }
 
+    /// Specifies how the components of generated keys are formatted.
+    ///
+    /// The default is [KeyFormat::Upper]. Note that [KeyFormat::Dotted] always
+    /// joins components with `.`, ignoring the configured separator.
+    ///
+    /// # Examples
+    /// ```
+    /// use confpiler::{FlatConfig, KeyFormat};
+    /// let mut builder = FlatConfig::builder();
+    /// builder.with_key_format(KeyFormat::ScreamingSnake); // maxConns -> MAX_CONNS
+    /// ```
+    pub fn with_key_format(&mut self, format: KeyFormat) -> &mut Self {
+        self.key_format = format;
+        self
+    }
+
     /// Specifies the separator to use when joining arrays
     ///
     /// This default array separator is `,`, and is used to join the values of
@@ -204,104 +673,566 @@ impl FlatConfigBuilder {
         self
     }
 
-    /// Specifies a prefix to be prepended to all generated keys.
+    /// Specifies how arrays are flattened.
     ///
-    /// This prefix will **always** be converted to ascii uppercase and will be
-    /// be separated from the rest of the generated key by the separator used
-    /// by the builder.
+    /// The default is [ArrayStrategy::Joined], which joins the values of an
+    /// array with the array separator.
     ///
     /// # Examples
     /// ```
-    /// use confpiler::FlatConfig;
+    /// use confpiler::{ArrayStrategy, FlatConfig};
     /// let mut builder = FlatConfig::builder();
-    /// builder.with_prefix("foo"); // this is the default
+    /// builder.with_array_strategy(ArrayStrategy::Indexed);
     /// ```
-    pub fn with_prefix(&mut self, prefix: &str) -> &mut Self {
-        self.prefix = Some(prefix.to_ascii_uppercase());
+    pub fn with_array_strategy(&mut self, strategy: ArrayStrategy) -> &mut Self {
+        self.array_strategy = strategy;
         self
     }
 
-    /// Attempt to produce a [FlatConfig] without consuming the builder.
+    /// Specifies the separator to use between the key of an array and the
+    /// index of an element when using [ArrayStrategy::Indexed] or
+    /// [ArrayStrategy::Both].
     ///
-    /// This results in an error in the following scenarios:
-    /// * No configs were specified.
-    /// * Flattening any given config results in a duplicate key within the same
-    /// file (`foo:` and `Foo:` in the same file, `foo_bar:` and `foo: bar:` in
-    /// the same file, etc.).
-    /// * A config contains an array that itself contains some nested structure.
-    /// * A config is invalid or not found as far as `config-rs` can determine.
+    /// If not specified, the separator used when flattening nested structures
+    /// is used.
     ///
     /// # Examples
     /// ```
     /// use confpiler::FlatConfig;
+    /// let mut builder = FlatConfig::builder();
+    /// builder.with_index_separator("_"); // FOO_0, FOO_1, etc.
     /// ```
-    pub fn build(&self) -> Result<(FlatConfig, Vec<MergeWarning>)> {
-        if self.configs.is_empty() {
-            return Err(ConfpilerError::NoConfigSpecified);
-        }
-
-        let mut seen_configs: HashSet<&str> = HashSet::new();
-
-        // the origin for the overall config will be whatever was first in
-        // the list
-        let mut flat_config = FlatConfig {
-            // this unwrap is safe because we just checked
-            origin: self.configs.first().unwrap().to_string(),
-            items: HashMap::new(),
-        };
-        let mut warnings = Vec::new();
-
-        for conf_path in self.configs.iter() {
-            // so this adds some complexity, but it's probably a better user
-            // experience?
-            if seen_configs.contains(conf_path.as_str()) {
-                return Err(ConfpilerError::DuplicateConfig(conf_path.to_string()));
-            } else {
-                seen_configs.insert(conf_path.as_str());
-            }
+    pub fn with_index_separator(&mut self, separator: &str) -> &mut Self {
+        self.index_separator = Some(separator.to_string());
+        self
+    }
 
-            // attempt to load every specified config
-            let conf = Config::builder()
-                .add_source(File::with_name(conf_path))
-                .build()?;
-
-            let input = conf.cache.into_table()?;
-
-            let mut out = HashMap::new();
-            flatten_into(
-                &input,
-                &mut out,
-                self.prefix.as_ref(),
-                &self.separator,
-                &self.array_separator,
-            )?;
-            let working_config = FlatConfig {
-                origin: conf_path.to_string(),
-                items: out,
-            };
+    /// Specifies a suffix used to generate an additional key containing the
+    /// number of elements in every array.
+    ///
+    /// The generated key is the key of the array, followed by the index
+    /// separator, followed by the suffix.
+    ///
+    /// # Examples
+    /// ```
+    /// use confpiler::FlatConfig;
+    /// let mut builder = FlatConfig::builder();
+    /// builder.with_array_length_suffix("LEN"); // FOO__LEN
+    /// ```
+    pub fn with_array_length_suffix(&mut self, suffix: &str) -> &mut Self {
+        self.array_length_suffix = Some(suffix.to_string());
+        self
+    }
 
-            let mut working_warnings = flat_config.merge(&working_config);
-            warnings.append(&mut working_warnings);
-        }
+    /// Specifies whether arrays containing maps or other arrays should be
+    /// encoded as compact JSON strings.
+    ///
+    /// By default, such arrays are unsupported and result in an error when
+    /// [build()](FlatConfigBuilder::build) is invoked. When enabled, the entire
+    /// array is encoded as a single JSON value, regardless of the
+    /// [ArrayStrategy]. Arrays of simple values are unaffected.
+    ///
+    /// # Examples
+    /// ```
+    /// use confpiler::FlatConfig;
+    /// let mut builder = FlatConfig::builder();
+    /// // upstreams: [{host: a, port: 80}] becomes
+    /// // UPSTREAMS='[{"host":"a","port":80}]'
+    /// builder.with_json_arrays(true);
+    /// ```
+    pub fn with_json_arrays(&mut self, enabled: bool) -> &mut Self {
+        self.json_arrays = enabled;
+        self
+    }
 
-        Ok((flat_config, warnings))
+    /// Marks the value at the given key to be encoded as a compact JSON string
+    /// instead of being flattened.
+    ///
+    /// The key is the flattened key as it would otherwise appear in the output
+    /// (including any prefix), and may refer to a map or an array.
+    ///
+    /// # Examples
+    /// ```
+    /// use confpiler::FlatConfig;
+    /// let mut builder = FlatConfig::builder();
+    /// // db: {replicas: {a: 1, b: 2}} becomes DB__REPLICAS='{"a":1,"b":2}'
+    /// builder.with_json_key("DB__REPLICAS");
+    /// ```
+    pub fn with_json_key(&mut self, key: &str) -> &mut Self {
+        self.json_keys.push(key.to_string());
+        self
     }
-}
 
-impl Default for FlatConfigBuilder {
-    fn default() -> Self {
-        Self {
-            prefix: None,
-            configs: Vec::new(),
-            separator: Self::DEFAULT_SEPARATOR.to_string(),
-            array_separator: Self::DEFAULT_ARRAY_SEPARATOR.to_string(),
-        }
+    /// Specifies a prefix to be prepended to all generated keys.
+    ///
+    /// This prefix will be formatted with the [KeyFormat] used by the builder
+    /// (meaning it is converted to ascii uppercase by default) and will be
+    /// separated from the rest of the generated key by the separator used by
+    /// the builder.
+    ///
+    /// # Examples
+    /// ```
+    /// use confpiler::FlatConfig;
+    /// let mut builder = FlatConfig::builder();
+    /// builder.with_prefix("foo"); // this is the default
+    /// ```
+    pub fn with_prefix(&mut self, prefix: &str) -> &mut Self {
+        self.prefix = Some(prefix.to_string());
+        self
     }
-}
 
-/// An enumeration of possible warning values regarding config merging.
-///
-/// These warnings occur as the result of merging two [FlatConfig] instances
+    /// Specifies how to handle keys introduced by any config other than the
+    /// first.
+    ///
+    /// The default is [NewKeyPolicy::Allow]. Setting this to something else is
+    /// useful for catching typos in configs that are only meant to override
+    /// existing values, like `databse: host:` in a `production` config.
+    ///
+    /// # Examples
+    /// ```
+    /// use confpiler::{FlatConfig, NewKeyPolicy};
+    /// let mut builder = FlatConfig::builder();
+    /// builder.with_new_key_policy(NewKeyPolicy::Warn);
+    /// ```
+    pub fn with_new_key_policy(&mut self, policy: NewKeyPolicy) -> &mut Self {
+        self.new_key_policy = policy;
+        self
+    }
+
+    /// Specifies how to handle configs that change the type of a value set by
+    /// a previous config.
+    ///
+    /// The default is [TypeChangePolicy::Warn]. Replacing an integer with a
+    /// float (or vice versa) is never considered a type change.
+    ///
+    /// # Examples
+    /// ```
+    /// use confpiler::{FlatConfig, TypeChangePolicy};
+    /// let mut builder = FlatConfig::builder();
+    /// builder.with_type_change_policy(TypeChangePolicy::Allow);
+    /// ```
+    pub fn with_type_change_policy(&mut self, policy: TypeChangePolicy) -> &mut Self {
+        self.type_change_policy = policy;
+        self
+    }
+
+    /// Specifies how to handle generated keys that are not valid environment
+    /// variable names.
+    ///
+    /// The default is [InvalidKeyPolicy::Allow]. Keys like `MY-APP__API.URL`
+    /// or `1FOO` are rejected by most shells, so setting this to something
+    /// else is useful if the output is meant to be exported or handed to a
+    /// container runtime.
+    ///
+    /// # Examples
+    /// ```
+    /// use confpiler::{FlatConfig, InvalidKeyPolicy};
+    /// let mut builder = FlatConfig::builder();
+    /// builder.with_invalid_key_policy(InvalidKeyPolicy::Sanitize);
+    /// ```
+    pub fn with_invalid_key_policy(&mut self, policy: InvalidKeyPolicy) -> &mut Self {
+        self.invalid_key_policy = policy;
+        self
+    }
+
+    /// Specifies whether `${KEY}` references in values should be resolved.
+    ///
+    /// This is disabled by default, in which case such references are left
+    /// as-is. References refer to other keys in the final, merged config (as
+    /// they would appear in the output), so a value like
+    /// `"postgres://${DB__HOST}/app"` reflects the value of `DB__HOST` after
+    /// all configs have been merged. A literal `${` can be written as `$${`.
+    ///
+    /// # Examples
+    /// ```
+    /// use confpiler::FlatConfig;
+    /// let mut builder = FlatConfig::builder();
+    /// builder.with_interpolation(true);
+    /// ```
+    pub fn with_interpolation(&mut self, interpolation: bool) -> &mut Self {
+        self.interpolation.keys = interpolation;
+        self
+    }
+
+    /// Specifies whether `${env:VAR}` references in values should be resolved
+    /// using the environment of the current process.
+    ///
+    /// This is disabled by default, in which case such references are left
+    /// as-is. Like the shell, `${env:VAR:-default}` provides a default for
+    /// when `VAR` is unset or empty, and `${env:VAR:?message}` results in an
+    /// error with the given message. Otherwise, unset variables result in an
+    /// error.
+    ///
+    /// # Examples
+    /// ```
+    /// use confpiler::FlatConfig;
+    /// let mut builder = FlatConfig::builder();
+    /// builder.with_env_interpolation(true);
+    /// ```
+    pub fn with_env_interpolation(&mut self, interpolation: bool) -> &mut Self {
+        self.interpolation.env = interpolation;
+        self
+    }
+
+    /// Specifies whether an explicit null (`~`, `null`, etc.) deletes a key set
+    /// by a previous config.
+    ///
+    /// This is disabled by default, in which case nulls are ignored. When
+    /// enabled, a null deletes the key, or, if it replaces a map or array,
+    /// every key generated from that map or array. Deletions are recorded in
+    /// the [Provenance] of the deleted keys, and a null that does not delete
+    /// anything generates a [MergeWarning::NothingDeleted] warning.
+    ///
+    /// # Examples
+    /// ```
+    /// use confpiler::FlatConfig;
+    /// let mut builder = FlatConfig::builder();
+    /// builder.with_null_deletes(true);
+    /// ```
+    pub fn with_null_deletes(&mut self, null_deletes: bool) -> &mut Self {
+        self.null_deletes = null_deletes;
+        self
+    }
+
+    /// Specifies a placeholder value marking keys that must be overridden by
+    /// a later config.
+    ///
+    /// This is useful for values in a default config that have no sensible
+    /// default, like credentials. If any key still has exactly this value
+    /// after all configs are merged, [build](FlatConfigBuilder::build) fails
+    /// with [ConfpilerError::UnresolvedPlaceholders], listing every such key.
+    ///
+    /// # Examples
+    /// ```
+    /// use confpiler::{FlatConfig, SourceFormat};
+    /// use confpiler::error::ConfpilerError;
+    ///
+    /// let res = FlatConfig::builder()
+    ///     .add_config_str("default", "USER=app\nPASSWORD=CHANGEME\n", SourceFormat::Dotenv)
+    ///     .add_config_str("production", "USER=prod\n", SourceFormat::Dotenv)
+    ///     .with_required_marker("CHANGEME")
+    ///     .build();
+    ///
+    /// assert!(matches!(
+    ///     res,
+    ///     Err(ConfpilerError::UnresolvedPlaceholders(keys)) if keys == vec!["PASSWORD"]
+    /// ));
+    /// ```
+    pub fn with_required_marker(&mut self, marker: &str) -> &mut Self {
+        self.required_marker = Some(marker.to_string());
+        self
+    }
+
+    /// Specifies a [Schema] that the final config must match.
+    ///
+    /// Validation happens after all configs are merged and all references are
+    /// resolved, and every violation is reported at once via
+    /// [ConfpilerError::SchemaViolations].
+    ///
+    /// # Examples
+    /// ```
+    /// use confpiler::{FlatConfig, Schema, SourceFormat};
+    /// # use confpiler::error::ConfpilerError;
+    /// # fn main() -> Result<(), ConfpilerError> {
+    /// let schema = Schema::from_str("PORT: int", SourceFormat::Yaml)?;
+    ///
+    /// let mut builder = FlatConfig::builder();
+    /// builder.with_schema(schema);
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn with_schema(&mut self, schema: Schema) -> &mut Self {
+        self.schema = Some(schema);
+        self
+    }
+
+    /// Attempt to produce a [FlatConfig] without consuming the builder.
+    ///
+    /// This results in an error in the following scenarios:
+    /// * No configs were specified.
+    /// * A directory does not exist or does not contain a default config.
+    /// * An environment has no config in any directory, if
+    ///   [with_require_environments](FlatConfigBuilder::with_require_environments)
+    ///   is set.
+    /// * Flattening any given config results in a duplicate key within the same
+    ///   file (`foo:` and `Foo:` in the same file, `foo_bar:` and `foo: bar:` in
+    ///   the same file, etc.).
+    /// * A config contains an array that itself contains some nested structure,
+    ///   unless [with_json_arrays](FlatConfigBuilder::with_json_arrays) is set.
+    /// * A config is invalid or not found as far as `config-rs` can determine.
+    /// * A dotenv config is invalid.
+    /// * A config other than the first introduces a new key and the
+    ///   [NewKeyPolicy] is [NewKeyPolicy::Deny].
+    /// * A generated key is not a valid environment variable name and the
+    ///   [InvalidKeyPolicy] is [InvalidKeyPolicy::Error].
+    /// * A `${KEY}` reference refers to a key that does not exist or the
+    ///   references form a cycle, if interpolation is enabled.
+    /// * Any key still has the value specified via
+    ///   [with_required_marker](FlatConfigBuilder::with_required_marker).
+    /// * A `${env:VAR}` reference refers to an unset variable without a
+    ///   default, if environment interpolation is enabled.
+    /// * The final config does not match the [Schema], if one was specified.
+    ///
+    /// # Examples
+    /// ```
+    /// use confpiler::FlatConfig;
+    /// ```
+    pub fn build(&self) -> Result<(FlatConfig, Vec<MergeWarning>)> {
+        if self.configs.is_empty() {
+            return Err(ConfpilerError::NoConfigSpecified);
+        }
+
+        let mut sources = Vec::with_capacity(self.configs.len());
+        for config in self.configs.iter() {
+            sources.append(&mut config.resolve(&self.default_basename, &self.environments)?);
+        }
+
+        if self.require_environments {
+            check_environments(&self.configs, &self.default_basename, &self.environments)?;
+        }
+
+        let mut seen_configs: HashSet<&str> = HashSet::new();
+
+        // the origin for the overall config will be whatever was first in
+        // the list
+        let mut flat_config = FlatConfig {
+            // this unwrap is safe because we just checked, and every config
+            // resolves to at least one source
+            origin: sources.first().unwrap().0.origin().to_string(),
+            layers: Vec::new(),
+            items: IndexMap::new(),
+            provenance: HashMap::new(),
+        };
+        let mut warnings = Vec::new();
+        let mut array_elements: HashMap<String, Vec<String>> = HashMap::new();
+        let mut environment_layers: HashSet<&str> = HashSet::new();
+        let options = self.flatten_options();
+
+        for (idx, (source, layer)) in sources.iter().enumerate() {
+            let conf_path = source.origin();
+
+            // so this adds some complexity, but it's probably a better user
+            // experience?
+            if seen_configs.contains(conf_path) {
+                return Err(ConfpilerError::DuplicateConfig(conf_path.to_string()));
+            } else {
+                seen_configs.insert(conf_path);
+            }
+
+            let mut out = Flattened::default();
+            let layer_options = FlattenOptions {
+                origin: conf_path,
+                ..options.clone()
+            };
+
+            // attempt to load every specified config
+            source.load(&mut out, &layer_options)?;
+
+            let provenance = out
+                .items
+                .iter()
+                .map(|(k, v)| {
+                    // every flattened value has a corresponding kind
+                    let value_type = out.kinds.get(k).copied().unwrap_or(ValueType::String);
+                    (k.clone(), Provenance::new(conf_path, v, value_type))
+                })
+                .collect();
+            let working_config = FlatConfig {
+                origin: conf_path.to_string(),
+                layers: vec![conf_path.to_string()],
+                items: out.items,
+                provenance,
+            };
+
+            if idx > 0 && self.new_key_policy != NewKeyPolicy::Allow {
+                // elements of arrays that were already defined are not new,
+                // even if the array got longer
+                let replaced_elements = out
+                    .arrays
+                    .iter()
+                    .filter(|(k, _)| array_elements.contains_key(*k))
+                    .flat_map(|(_, elements)| elements.iter())
+                    .collect::<HashSet<_>>();
+
+                // keys with any provenance were set by a previous config, even
+                // if they have since been deleted
+                let mut new_keys = working_config
+                    .items
+                    .keys()
+                    .filter(|k| !flat_config.provenance.contains_key(*k))
+                    .filter(|k| !replaced_elements.contains(k))
+                    .map(|k| MergeWarning::NewKey {
+                        origin: conf_path.to_string(),
+                        key: k.to_string(),
+                    })
+                    .collect::<Vec<_>>();
+
+                if !new_keys.is_empty() && self.new_key_policy == NewKeyPolicy::Deny {
+                    return Err(ConfpilerError::from(new_keys));
+                }
+
+                warnings.append(&mut new_keys);
+            }
+
+            // deleting keys before merging makes no difference, since a config
+            // can't both set and delete the same key. The keys generated for
+            // an array are deleted by name rather than by prefix, since the
+            // index separator may also separate siblings like `HOOF_BAR`
+            for key in out.nulls.iter() {
+                let generated = array_elements.remove(key).unwrap_or_default();
+                let deleted_generated = flat_config.delete_keys(generated.iter(), conf_path);
+
+                if !flat_config.delete(key, conf_path, options.separator) && !deleted_generated {
+                    warnings.push(MergeWarning::NothingDeleted {
+                        origin: conf_path.to_string(),
+                        key: key.to_string(),
+                    });
+                }
+            }
+
+            // arrays are replaced, not merged, so when a config sets a key that
+            // was an array (to anything, not just another array), the keys
+            // generated for the old array that aren't set again have to be
+            // removed, which is recorded like a deletion so the history is kept
+            let replaced = array_elements
+                .keys()
+                .filter(|key| {
+                    out.arrays.contains_key(*key)
+                        || working_config.items.keys().any(|k| {
+                            k.strip_prefix(key.as_str()).map_or(false, |rest| {
+                                rest.is_empty() || rest.starts_with(options.separator)
+                            })
+                        })
+                })
+                .cloned()
+                .collect::<Vec<_>>();
+
+            for key in replaced {
+                if let Some(previous) = array_elements.remove(&key) {
+                    let stale = previous
+                        .iter()
+                        .filter(|e| !working_config.items.contains_key(*e));
+                    flat_config.delete_keys(stale, conf_path);
+                }
+            }
+
+            array_elements.extend(out.arrays);
+
+            // the default of a later directory replacing the value from an
+            // environment means that value can never be seen, regardless of
+            // which environment is built
+            if *layer == Layer::Default {
+                warnings.append(&mut flat_config.shadowed_by(&working_config, &environment_layers));
+            }
+
+            let mut working_warnings = flat_config.merge(&working_config);
+
+            if self.type_change_policy != TypeChangePolicy::Warn {
+                let (type_changes, others): (Vec<_>, Vec<_>) = working_warnings
+                    .into_iter()
+                    .partition(|w| matches!(w, MergeWarning::TypeChanged { .. }));
+
+                if !type_changes.is_empty() && self.type_change_policy == TypeChangePolicy::Deny {
+                    return Err(ConfpilerError::from(type_changes));
+                }
+
+                working_warnings = others;
+            }
+
+            warnings.append(&mut working_warnings);
+
+            if *layer == Layer::Environment {
+                environment_layers.insert(conf_path);
+            }
+        }
+
+        // placeholders are checked before interpolation, so that only the keys
+        // that were actually left unset are reported
+        if let Some(ref marker) = self.required_marker {
+            let unresolved = flat_config
+                .items
+                .iter()
+                .filter(|(_, v)| *v == marker)
+                .map(|(k, _)| k.to_string())
+                .collect::<Vec<_>>();
+
+            if !unresolved.is_empty() {
+                return Err(ConfpilerError::UnresolvedPlaceholders(unresolved));
+            }
+        }
+
+        // references are resolved after everything is merged so that
+        // overrides are reflected in any values that refer to them
+        if self.interpolation.is_enabled() {
+            interpolate(&mut flat_config.items, self.interpolation)?;
+        }
+
+        if let Some(ref schema) = self.schema {
+            let violations = schema.validate(&flat_config);
+
+            if !violations.is_empty() {
+                return Err(ConfpilerError::SchemaViolations(violations));
+            }
+        }
+
+        Ok((flat_config, warnings))
+    }
+}
+
+impl FlatConfigBuilder {
+    fn flatten_options(&self) -> FlattenOptions<'_> {
+        let separator = self.key_format.separator().unwrap_or(&self.separator);
+
+        FlattenOptions {
+            origin: "",
+            prefix: self.prefix.as_deref(),
+            key_format: self.key_format.clone(),
+            separator,
+            array_separator: &self.array_separator,
+            array_strategy: self.array_strategy,
+            index_separator: self.index_separator.as_deref().unwrap_or(separator),
+            array_length_suffix: self.array_length_suffix.as_deref(),
+            json_arrays: self.json_arrays,
+            json_keys: &self.json_keys,
+            invalid_key_policy: self.invalid_key_policy,
+            null_deletes: self.null_deletes,
+        }
+    }
+}
+
+impl Default for FlatConfigBuilder {
+    fn default() -> Self {
+        Self {
+            prefix: None,
+            configs: Vec::new(),
+            default_basename: Self::DEFAULT_BASENAME.to_string(),
+            environments: Vec::new(),
+            require_environments: false,
+            key_format: KeyFormat::default(),
+            separator: Self::DEFAULT_SEPARATOR.to_string(),
+            array_separator: Self::DEFAULT_ARRAY_SEPARATOR.to_string(),
+            array_strategy: ArrayStrategy::default(),
+            index_separator: None,
+            array_length_suffix: None,
+            json_arrays: false,
+            json_keys: Vec::new(),
+            new_key_policy: NewKeyPolicy::default(),
+            type_change_policy: TypeChangePolicy::default(),
+            invalid_key_policy: InvalidKeyPolicy::default(),
+            interpolation: Interpolation {
+                keys: false,
+                env: false,
+            },
+            null_deletes: false,
+            required_marker: None,
+            schema: None,
+        }
+    }
+}
+
+/// An enumeration of possible warning values regarding config merging.
+///
+/// These warnings occur as the result of merging two [FlatConfig] instances
 /// together. They are not necessarily errors, but are provided for the caller
 /// to treat as such if they wish.
 ///
@@ -323,6 +1254,63 @@ pub enum MergeWarning {
         key: String,
         value: String,
     },
+
+    /// This variant indicates that a value set by an environment-specific
+    /// config was replaced by the default config of a later directory before
+    /// reaching the final config. This is useful for detecting "dead"
+    /// overrides, such as when merging
+    /// `dir1/default -> dir1/production -> dir2/default`, where
+    /// `dir1/production` and `dir2/default` both set the same key, meaning the
+    /// value from `dir1/production` is never used.
+    ///
+    /// Overriding values within a chain of layers (like
+    /// `global -> dir/default -> dir/production`, or inherited and stacked
+    /// environments) is the point of layering, and does not generate this
+    /// warning.
+    ///
+    /// This is only generated by [FlatConfigBuilder::build], as
+    /// [FlatConfig::merge] does not know which configs are environments.
+    ShadowedOverride {
+        origin: String,
+        overrider: String,
+        key: String,
+        value: String,
+    },
+
+    /// This variant indicates that a config other than the first introduced a
+    /// key that was not defined by any previous config. This is useful for
+    /// detecting typos in configs that are only meant to override values.
+    ///
+    /// This is only generated by [FlatConfigBuilder::build] when the
+    /// [NewKeyPolicy] is not [NewKeyPolicy::Allow].
+    NewKey { origin: String, key: String },
+
+    /// This variant indicates that a config changed the type of a value set by
+    /// a previous config, like replacing `port: 5432` with `port: "five"`.
+    /// Because all values are converted to strings, this would otherwise go
+    /// unnoticed.
+    ///
+    /// Formats without typed values (like INI) produce only strings, so mixing
+    /// those with typed formats will likely generate these warnings. Integers
+    /// and floats are considered compatible, so replacing one with the other
+    /// does not generate this warning.
+    ///
+    /// [FlatConfigBuilder::build] only generates these when the
+    /// [TypeChangePolicy] is [TypeChangePolicy::Warn].
+    TypeChanged {
+        origin: String,
+        overrider: String,
+        key: String,
+        from: ValueType,
+        to: ValueType,
+    },
+
+    /// This variant indicates that a config set a key to null, but there was
+    /// no key to delete.
+    ///
+    /// This is only generated by [FlatConfigBuilder::build] when nulls delete
+    /// keys (see [FlatConfigBuilder::with_null_deletes]).
+    NothingDeleted { origin: String, key: String },
 }
 
 impl fmt::Display for MergeWarning {
@@ -335,232 +1323,939 @@ impl fmt::Display for MergeWarning {
             } => {
                 write!(f, "'{overrider}' is attempting to override '{key}' with '{value}', but the key already contains that value")
             }
+            Self::ShadowedOverride {
+                ref origin,
+                ref overrider,
+                ref key,
+                ref value,
+            } => {
+                write!(f, "'{overrider}' is overriding '{key}', shadowing the value '{value}' from '{origin}', which will never be used")
+            }
+            Self::NewKey {
+                ref origin,
+                ref key,
+            } => {
+                write!(f, "'{origin}' is introducing '{key}', which was not defined by any previous config")
+            }
+            Self::TypeChanged {
+                ref origin,
+                ref overrider,
+                ref key,
+                ref from,
+                ref to,
+            } => {
+                write!(f, "'{overrider}' is changing the type of '{key}' from {from} (set by '{origin}') to {to}")
+            }
+            Self::NothingDeleted {
+                ref origin,
+                ref key,
+            } => {
+                write!(
+                    f,
+                    "'{origin}' is deleting '{key}', which was not defined by any previous config"
+                )
+            }
+        }
+    }
+}
+
+/// Determines how [FlatConfigBuilder::build] treats keys introduced by configs
+/// other than the first.
+#[derive(Debug, Clone, Copy, Default, Eq, PartialEq)]
+pub enum NewKeyPolicy {
+    /// New keys are allowed without generating warnings.
+    #[default]
+    Allow,
+
+    /// New keys generate [MergeWarning::NewKey] warnings.
+    Warn,
+
+    /// New keys result in an error.
+    Deny,
+}
+
+impl NewKeyPolicy {
+    /// The string representations of the variants, as accepted by
+    /// [from_str](std::str::FromStr::from_str).
+    pub const VARIANTS: &'static [&'static str] = &["allow", "warn", "deny"];
+}
+
+impl std::str::FromStr for NewKeyPolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s {
+            "allow" => Ok(Self::Allow),
+            "warn" => Ok(Self::Warn),
+            "deny" => Ok(Self::Deny),
+            _ => Err(format!(
+                "unknown policy \"{s}\" (expected one of: {})",
+                Self::VARIANTS.join(", ")
+            )),
         }
     }
 }
 
+/// Determines how [FlatConfigBuilder::build] treats configs that change the
+/// type of a value set by a previous config.
+#[derive(Debug, Clone, Copy, Default, Eq, PartialEq)]
+pub enum TypeChangePolicy {
+    /// Type changes are allowed without generating warnings.
+    Allow,
+
+    /// Type changes generate [MergeWarning::TypeChanged] warnings.
+    #[default]
+    Warn,
+
+    /// Type changes result in an error.
+    Deny,
+}
+
+impl TypeChangePolicy {
+    /// The string representations of the variants, as accepted by
+    /// [from_str](std::str::FromStr::from_str).
+    pub const VARIANTS: &'static [&'static str] = &["allow", "warn", "deny"];
+}
+
+impl std::str::FromStr for TypeChangePolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s {
+            "allow" => Ok(Self::Allow),
+            "warn" => Ok(Self::Warn),
+            "deny" => Ok(Self::Deny),
+            _ => Err(format!(
+                "unknown policy \"{s}\" (expected one of: {})",
+                Self::VARIANTS.join(", ")
+            )),
+        }
+    }
+}
+
+/// Determines how [FlatConfigBuilder::build] flattens arrays.
+#[derive(Debug, Clone, Copy, Default, Eq, PartialEq)]
+pub enum ArrayStrategy {
+    /// Arrays are joined into a single value using the array separator, so
+    /// `foo: [a, b]` becomes `FOO="a,b"`.
+    #[default]
+    Joined,
+
+    /// Every element of an array is given its own key, using the index
+    /// separator, so `foo: [a, b]` becomes `FOO__0="a"` and `FOO__1="b"`.
+    Indexed,
+
+    /// Arrays are flattened as both [ArrayStrategy::Joined] and
+    /// [ArrayStrategy::Indexed].
+    Both,
+}
+
+impl ArrayStrategy {
+    /// The string representations of the variants, as accepted by
+    /// [from_str](std::str::FromStr::from_str).
+    pub const VARIANTS: &'static [&'static str] = &["joined", "indexed", "both"];
+
+    fn is_joined(&self) -> bool {
+        matches!(self, Self::Joined | Self::Both)
+    }
+
+    fn is_indexed(&self) -> bool {
+        matches!(self, Self::Indexed | Self::Both)
+    }
+}
+
+impl std::str::FromStr for ArrayStrategy {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s {
+            "joined" => Ok(Self::Joined),
+            "indexed" => Ok(Self::Indexed),
+            "both" => Ok(Self::Both),
+            _ => Err(format!(
+                "unknown array strategy \"{s}\" (expected one of: {})",
+                Self::VARIANTS.join(", ")
+            )),
+        }
+    }
+}
+
+/// The result of flattening a single config.
+#[derive(Debug, Default)]
+pub(crate) struct Flattened {
+    pub(crate) items: IndexMap<String, String>,
+    pub(crate) kinds: HashMap<String, ValueType>,
+    /// The keys generated for each array besides the array itself (indexed
+    /// elements and the length), so that they can be removed when a later
+    /// config replaces or deletes the array.
+    pub(crate) arrays: HashMap<String, Vec<String>>,
+    /// The keys set to null, if nulls delete keys.
+    pub(crate) nulls: Vec<String>,
+}
+
+impl Flattened {
+    pub(crate) fn insert(&mut self, key: String, value: String, kind: ValueType) -> Result<()> {
+        if self.items.contains_key(&key) {
+            return Err(ConfpilerError::DuplicateKey(key));
+        }
+
+        self.kinds.insert(key.clone(), kind);
+        self.items.insert(key, value);
+
+        Ok(())
+    }
+}
+
+/// The settings that control how a config is flattened.
+#[derive(Debug, Clone)]
+pub(crate) struct FlattenOptions<'a> {
+    /// The config being flattened, for error reporting.
+    pub(crate) origin: &'a str,
+    pub(crate) prefix: Option<&'a str>,
+    pub(crate) key_format: KeyFormat,
+    pub(crate) separator: &'a str,
+    pub(crate) array_separator: &'a str,
+    pub(crate) array_strategy: ArrayStrategy,
+    pub(crate) index_separator: &'a str,
+    pub(crate) array_length_suffix: Option<&'a str>,
+    pub(crate) json_arrays: bool,
+    pub(crate) json_keys: &'a [String],
+    pub(crate) invalid_key_policy: InvalidKeyPolicy,
+    pub(crate) null_deletes: bool,
+}
+
+impl Default for FlattenOptions<'_> {
+    fn default() -> Self {
+        Self {
+            origin: "",
+            prefix: None,
+            key_format: KeyFormat::default(),
+            separator: FlatConfigBuilder::DEFAULT_SEPARATOR,
+            array_separator: FlatConfigBuilder::DEFAULT_ARRAY_SEPARATOR,
+            array_strategy: ArrayStrategy::default(),
+            index_separator: FlatConfigBuilder::DEFAULT_SEPARATOR,
+            array_length_suffix: None,
+            json_arrays: false,
+            json_keys: &[],
+            invalid_key_policy: InvalidKeyPolicy::default(),
+            null_deletes: false,
+        }
+    }
+}
+
+impl FlattenOptions<'_> {
+    /// Sanitize the given key if the [InvalidKeyPolicy] calls for it.
+    fn sanitize(&self, key: String) -> String {
+        if self.invalid_key_policy == InvalidKeyPolicy::Sanitize && !is_valid_env_name(&key) {
+            sanitize_env_name(&key)
+        } else {
+            key
+        }
+    }
+
+    /// Apply the [InvalidKeyPolicy] to a key that is about to be output.
+    pub(crate) fn checked_key(&self, key: String, source_key: &str) -> Result<String> {
+        let key = self.sanitize(key);
+
+        if self.invalid_key_policy == InvalidKeyPolicy::Error && !is_valid_env_name(&key) {
+            return Err(ConfpilerError::InvalidKey {
+                key,
+                source_key: source_key.to_string(),
+                origin: self.origin.to_string(),
+            });
+        }
+
+        Ok(key)
+    }
+}
+
 pub(crate) fn flatten_into(
-    input: &HashMap<String, Value>,
-    output: &mut HashMap<String, String>,
-    prefix: Option<&String>,
-    separator: &str,
-    array_separator: &str,
+    input: &Map<String, Value>,
+    output: &mut Flattened,
+    options: &FlattenOptions,
 ) -> Result<()> {
     let mut components = Vec::new();
-    if let Some(prefix) = prefix {
-        components.push(prefix.clone());
+    if let Some(prefix) = options.prefix {
+        components.push(options.key_format.format(prefix));
     }
-    flatten_into_inner(input, output, separator, array_separator, &mut components)
+    flatten_into_inner(input, output, options, &mut components, &mut Vec::new())
 }
 
-fn flatten_into_inner(
-    input: &HashMap<String, Value>,
-    output: &mut HashMap<String, String>,
-    separator: &str,
-    array_separator: &str,
+fn flatten_into_inner<'a>(
+    input: &'a Map<String, Value>,
+    output: &mut Flattened,
+    options: &FlattenOptions,
     components: &mut Vec<String>,
+    path: &mut Vec<&'a str>,
 ) -> Result<()> {
     if input.is_empty() {
         return Ok(());
     }
 
-    for (key, value) in input.iter() {
-        // convert the current key to uppercase and add it to the list of
-        // components so that we can form names with the current "path"
-        let upper_key = key.to_ascii_uppercase();
-        components.push(upper_key);
-        match &value.kind {
-            // omit these because they have no meaning
-            ValueKind::Nil => {}
+    for (key, value) in input.iter() {
+        // format the current key (uppercase, by default) and add it to the
+        // list of components so that we can form names with the current "path"
+        components.push(options.key_format.format(key));
+        // we also track the unformatted path for error reporting
+        path.push(key);
+        match &value.kind {
+            // omit these because they have no meaning, unless we're using them
+            // to delete keys from previous configs
+            ValueKind::Nil => {
+                if options.null_deletes {
+                    let candidate = components.join(options.separator);
+                    let key = options.checked_key(candidate, &path.join("."))?;
+                    output.nulls.push(key);
+                }
+            }
+
+            // If we encounter another table, we just need to recurse, unless
+            // the table was explicitly marked to be encoded as json
+            ValueKind::Table(ref table) => {
+                let candidate = options.sanitize(components.join(options.separator));
+
+                if options.json_keys.iter().any(|k| k == &candidate) {
+                    let key = options.checked_key(candidate, &path.join("."))?;
+                    output.insert(key, to_json(value)?, ValueType::Table)?;
+                } else {
+                    flatten_into_inner(table, output, options, components, path)?;
+                }
+            }
+
+            // Arrays are only supported if they contain primitive/str types
+            // because what does it actually mean to flatten an array into
+            // separate environment variables? Depending on the strategy, we
+            // either convert the array into a sequence-separated string, or
+            // we do something like
+            // FOO__0 = "a"
+            // FOO__1 = "b"
+            // FOO__2 = "c"
+            // etc., but what would that do when some arbitrary index is a
+            // complex type like an array or a map? So both strategies limit
+            // the kinds of things we can store in an array
+            //
+            // If we're allowed to, we can get around these limitations by
+            // encoding the array as json, leaving the parsing to the consumer
+            ValueKind::Array(ref array) => {
+                let candidate = options.sanitize(components.join(options.separator));
+                let source_key = path.join(".");
+
+                if is_json_array(&candidate, array, options) {
+                    let key = options.checked_key(candidate, &source_key)?;
+                    output.insert(key, to_json(value)?, ValueType::Array)?;
+                } else {
+                    flatten_array(candidate, &source_key, array, output, options)?;
+                }
+            }
+
+            // for everything else, we want to add the key/value to the output
+            _ => {
+                let candidate = components.join(options.separator);
+                let key = options.checked_key(candidate, &path.join("."))?;
+
+                // this clone might be unnecessary and we could just convert
+                // directly into a string, but I think I want the error to be
+                // raised if the interface changes to not allow arbitrary things
+                // to be converted to string.
+                output.insert(key, value.clone().into_string()?, value_type(&value.kind))?;
+            }
+        }
+
+        // we have to remove the key we pushed
+        components.pop();
+        path.pop();
+    }
+
+    Ok(())
+}
+
+fn flatten_array(
+    candidate: String,
+    source_key: &str,
+    array: &[Value],
+    output: &mut Flattened,
+    options: &FlattenOptions,
+) -> Result<()> {
+    let vals = array
+        .iter()
+        .cloned()
+        .map(|e| e.into_string())
+        .collect::<std::result::Result<Vec<String>, ConfigError>>()
+        // TODO: this is actually an assumption about why this would fail - MCL - 2022-02-21
+        .map_err(|_| ConfpilerError::UnsupportedArray(candidate.clone()))?;
+
+    let mut generated = Vec::new();
+
+    if options.array_strategy.is_indexed() {
+        for (idx, (element, val)) in array.iter().zip(vals.iter()).enumerate() {
+            let element_key = options.checked_key(
+                format!("{candidate}{}{idx}", options.index_separator),
+                source_key,
+            )?;
+            output.insert(element_key.clone(), val.clone(), value_type(&element.kind))?;
+            generated.push(element_key);
+        }
+    }
+
+    if let Some(suffix) = options.array_length_suffix {
+        let length_key = options.checked_key(
+            format!("{candidate}{}{suffix}", options.index_separator),
+            source_key,
+        )?;
+        output.insert(
+            length_key.clone(),
+            vals.len().to_string(),
+            ValueType::Integer,
+        )?;
+        generated.push(length_key);
+    }
+
+    output.arrays.insert(candidate.clone(), generated);
+
+    if options.array_strategy.is_joined() {
+        output.insert(
+            options.checked_key(candidate, source_key)?,
+            vals.join(options.array_separator),
+            ValueType::Array,
+        )?;
+    }
+
+    Ok(())
+}
+
+fn is_json_array(key: &str, array: &[Value], options: &FlattenOptions) -> bool {
+    options.json_keys.iter().any(|k| k == key)
+        || (options.json_arrays
+            && array
+                .iter()
+                .any(|e| matches!(e.kind, ValueKind::Array(_) | ValueKind::Table(_))))
+}
+
+fn to_json(value: &Value) -> Result<String> {
+    let json = value.clone().try_deserialize::<serde_json::Value>()?;
+
+    // serializing a serde_json::Value to a string should not be able to fail
+    Ok(json.to_string())
+}
+
+fn value_type(kind: &ValueKind) -> ValueType {
+    match kind {
+        ValueKind::Boolean(_) => ValueType::Boolean,
+        ValueKind::I64(_) | ValueKind::I128(_) | ValueKind::U64(_) | ValueKind::U128(_) => {
+            ValueType::Integer
+        }
+        ValueKind::Float(_) => ValueType::Float,
+        ValueKind::Array(_) => ValueType::Array,
+        // tables are either flattened or encoded as json (which is typed as a
+        // table by the caller), so this is only for completeness
+        ValueKind::Table(_) => ValueType::Table,
+        // nils are never converted to values, so they're only here for
+        // completeness
+        ValueKind::String(_) | ValueKind::Nil => ValueType::String,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    mod flat_config {
+        use super::super::*;
+        use crate::provenance::Assignment;
+
+        fn flat(origin: &str, items: &[(&str, &str)]) -> FlatConfig {
+            FlatConfig {
+                origin: origin.to_string(),
+                layers: vec![origin.to_string()],
+                items: items
+                    .iter()
+                    .map(|(k, v)| (k.to_string(), v.to_string()))
+                    .collect(),
+                provenance: items
+                    .iter()
+                    .map(|(k, v)| (k.to_string(), Provenance::new(origin, v, ValueType::String)))
+                    .collect(),
+            }
+        }
+
+        #[test]
+        fn builder_yields_a_default_builder() {
+            assert_eq!(FlatConfig::builder(), FlatConfigBuilder::default());
+        }
+
+        #[test]
+        fn merging() {
+            let mut a = flat("origin1", &[("herp", "derp"), ("hoof", "changeme")]);
+            let b = flat("origin2", &[("foo", "bar"), ("hoof", "doof")]);
+
+            let mut hoof = Provenance::new("origin1", "changeme", ValueType::String);
+            hoof.extend(&Provenance::new("origin2", "doof", ValueType::String));
+
+            let expected = FlatConfig {
+                origin: "origin1".to_string(),
+                layers: vec!["origin1".to_string(), "origin2".to_string()],
+                items: IndexMap::from([
+                    ("herp".to_string(), "derp".to_string()),
+                    ("hoof".to_string(), "doof".to_string()),
+                    ("foo".to_string(), "bar".to_string()),
+                ]),
+                provenance: HashMap::from([
+                    (
+                        "foo".to_string(),
+                        Provenance::new("origin2", "bar", ValueType::String),
+                    ),
+                    ("hoof".to_string(), hoof),
+                    (
+                        "herp".to_string(),
+                        Provenance::new("origin1", "derp", ValueType::String),
+                    ),
+                ]),
+            };
+
+            let warnings = a.merge(&b);
+
+            assert_eq!(a, expected);
+            // IndexMap equality ignores order, so the order is checked
+            // separately: overridden keys stay put, new keys are appended
+            assert_eq!(
+                a.items.keys().collect::<Vec<_>>(),
+                vec!["herp", "hoof", "foo"]
+            );
+            assert!(warnings.is_empty());
+        }
+
+        #[test]
+        fn merging_when_overriding_with_same_value_generates_warnings() {
+            let mut a = flat("origin1", &[("herp", "derp"), ("hoof", "changeme")]);
+            let b = flat(
+                "origin2",
+                &[("foo", "bar"), ("herp", "derp"), ("hoof", "changeme")],
+            );
+
+            let mut herp = Provenance::new("origin1", "derp", ValueType::String);
+            herp.extend(&Provenance::new("origin2", "derp", ValueType::String));
+            let mut hoof = Provenance::new("origin1", "changeme", ValueType::String);
+            hoof.extend(&Provenance::new("origin2", "changeme", ValueType::String));
+
+            let expected = FlatConfig {
+                origin: "origin1".to_string(),
+                layers: vec!["origin1".to_string(), "origin2".to_string()],
+                items: IndexMap::from([
+                    ("herp".to_string(), "derp".to_string()),
+                    ("hoof".to_string(), "changeme".to_string()),
+                    ("foo".to_string(), "bar".to_string()),
+                ]),
+                provenance: HashMap::from([
+                    (
+                        "foo".to_string(),
+                        Provenance::new("origin2", "bar", ValueType::String),
+                    ),
+                    ("herp".to_string(), herp),
+                    ("hoof".to_string(), hoof),
+                ]),
+            };
+
+            let warnings = a.merge(&b);
+
+            assert_eq!(a, expected);
+            assert_eq!(
+                a.items.keys().collect::<Vec<_>>(),
+                vec!["herp", "hoof", "foo"]
+            );
+
+            assert_eq!(warnings.len(), 2);
+
+            // we're sensitive to ordering here because of the hashing, so just
+            // assert individually
+            assert!(warnings.contains(&MergeWarning::RedundantValue {
+                overrider: "origin2".to_string(),
+                key: "herp".to_string(),
+                value: "derp".to_string(),
+            }));
+
+            assert!(warnings.contains(&MergeWarning::RedundantValue {
+                overrider: "origin2".to_string(),
+                key: "hoof".to_string(),
+                value: "changeme".to_string(),
+            }));
+        }
+
+        #[test]
+        fn merging_over_an_override_does_not_generate_warnings() {
+            let mut a = flat("origin1", &[("herp", "derp"), ("hoof", "changeme")]);
+            let b = flat("origin2", &[("herp", "derp2"), ("hoof", "doof")]);
+            let c = flat("origin3", &[("hoof", "doof2")]);
+
+            // overriding previous values is the whole point of layering
+            assert!(a.merge(&b).is_empty());
+            assert!(a.merge(&c).is_empty());
+        }
+
+        #[test]
+        fn detecting_shadowed_overrides() {
+            let mut a = flat("origin1", &[("herp", "derp"), ("hoof", "changeme")]);
+            let b = flat("origin2", &[("herp", "derp2"), ("hoof", "doof")]);
+            let c = flat("origin3", &[("herp", "derp2"), ("hoof", "doof2")]);
+
+            a.merge(&b);
+
+            // only values from environment-specific configs can be shadowed
+            assert!(a.shadowed_by(&c, &HashSet::new()).is_empty());
+
+            assert_eq!(
+                a.shadowed_by(&c, &HashSet::from(["origin2"])),
+                vec![MergeWarning::ShadowedOverride {
+                    origin: "origin2".to_string(),
+                    overrider: "origin3".to_string(),
+                    key: "hoof".to_string(),
+                    value: "doof".to_string(),
+                }]
+            );
+        }
+
+        #[test]
+        fn merging_with_a_different_type_generates_warnings() {
+            let mut a = flat("origin1", &[("herp", "derp")]);
+            let mut b = flat("origin2", &[("herp", "10")]);
+            b.provenance.insert(
+                "herp".to_string(),
+                Provenance::new("origin2", "10", ValueType::Integer),
+            );
+
+            let warnings = a.merge(&b);
+
+            assert_eq!(
+                warnings,
+                vec![MergeWarning::TypeChanged {
+                    origin: "origin1".to_string(),
+                    overrider: "origin2".to_string(),
+                    key: "herp".to_string(),
+                    from: ValueType::String,
+                    to: ValueType::Integer,
+                }]
+            );
+            assert_eq!(
+                a.provenance("herp").unwrap().value_type(),
+                ValueType::Integer
+            );
+        }
+
+        #[test]
+        fn merging_numbers_does_not_generate_type_warnings() {
+            let mut a = flat("origin1", &[("herp", "10"), ("hoof", "1.5")]);
+            a.provenance.insert(
+                "herp".to_string(),
+                Provenance::new("origin1", "10", ValueType::Integer),
+            );
+            a.provenance.insert(
+                "hoof".to_string(),
+                Provenance::new("origin1", "1.5", ValueType::Float),
+            );
+
+            let mut b = flat("origin2", &[("herp", "10.5"), ("hoof", "2")]);
+            b.provenance.insert(
+                "herp".to_string(),
+                Provenance::new("origin2", "10.5", ValueType::Float),
+            );
+            b.provenance.insert(
+                "hoof".to_string(),
+                Provenance::new("origin2", "2", ValueType::Integer),
+            );
+
+            assert!(a.merge(&b).is_empty());
+        }
+
+        #[test]
+        fn merging_tracks_provenance() {
+            let mut a = FlatConfig::default();
+            let b = flat("origin1", &[("herp", "derp"), ("hoof", "changeme")]);
+            let c = flat("origin2", &[("hoof", "changeme")]);
+            let d = flat("origin3", &[("hoof", "doof")]);
+
+            a.merge(&b);
+            a.merge(&c);
+            a.merge(&d);
+
+            assert_eq!(a.origin_of("herp"), Some("origin1"));
+            assert_eq!(a.origin_of("hoof"), Some("origin3"));
+            assert_eq!(a.origin_of("foo"), None);
+
+            let hoof = a.provenance("hoof").expect("missing provenance");
+            assert_eq!(
+                hoof.assignments(),
+                &[
+                    Assignment::new("origin1", "changeme", ValueType::String),
+                    Assignment::new("origin2", "changeme", ValueType::String),
+                    Assignment::new("origin3", "doof", ValueType::String),
+                ]
+            );
+            assert_eq!(
+                hoof.origins().collect::<Vec<_>>(),
+                vec!["origin1", "origin2", "origin3"]
+            );
+        }
+
+        #[test]
+        fn merging_without_provenance_attributes_to_origin() {
+            let mut a = FlatConfig::default();
+            let b = FlatConfig {
+                origin: "origin1".to_string(),
+                layers: Vec::new(),
+                items: IndexMap::from([("foo".to_string(), "bar".to_string())]),
+                provenance: HashMap::new(),
+            };
+
+            a.merge(&b);
+
+            assert_eq!(
+                a.provenance("foo"),
+                Some(&Provenance::new("origin1", "bar", ValueType::String))
+            );
+        }
+    }
+
+    mod flat_config_builder {
+        use super::super::*;
+
+        #[test]
+        fn defaults() {
+            let builder = FlatConfigBuilder::default();
+            assert!(builder.configs.is_empty());
+            assert_eq!(builder.separator, "__".to_string());
+            assert_eq!(builder.array_separator, ",".to_string());
+        }
+
+        #[test]
+        fn adding_configs() {
+            let mut builder = FlatConfigBuilder::default();
+            builder.add_config("foo/bar");
+            builder.add_config("foo/baz");
+
+            let expected = vec![
+                Source::Path("foo/bar".to_string()),
+                Source::Path("foo/baz".to_string()),
+            ];
+
+            assert_eq!(builder.configs, expected);
+        }
+
+        #[test]
+        fn adding_directories() {
+            let mut builder = FlatConfigBuilder::default();
+            builder.add_config("foo/bar");
+            builder.add_directory("foo");
+
+            let expected = vec![
+                Source::Path("foo/bar".to_string()),
+                Source::Directory("foo".to_string()),
+            ];
+
+            assert_eq!(builder.configs, expected);
+        }
+
+        #[test]
+        fn specifying_default_basename() {
+            let mut builder = FlatConfigBuilder::default();
+            assert_eq!(builder.default_basename, "default".to_string());
+
+            builder.with_default_basename("base");
+
+            assert_eq!(builder.default_basename, "base".to_string());
+        }
+
+        #[test]
+        fn specifying_environments() {
+            let mut builder = FlatConfigBuilder::default();
+            assert!(builder.environments.is_empty());
+
+            builder.with_environment("production");
+            builder.with_environment("production-east");
+
+            assert_eq!(
+                builder.environments,
+                vec!["production".to_string(), "production-east".to_string()]
+            );
+        }
+
+        #[test]
+        fn specifying_require_environments() {
+            let mut builder = FlatConfigBuilder::default();
+            assert!(!builder.require_environments);
+
+            builder.with_require_environments(true);
+
+            assert!(builder.require_environments);
+        }
+
+        #[test]
+        fn adding_in_memory_configs() {
+            let mut builder = FlatConfigBuilder::default();
+            builder.add_config_str("foo", "FOO=bar", SourceFormat::Dotenv);
+            builder.add_config_value("bar", serde_json::Map::new());
+
+            let origins = builder
+                .configs
+                .iter()
+                .map(|c| c.origin())
+                .collect::<Vec<_>>();
+
+            assert_eq!(origins, vec!["foo", "bar"]);
+        }
+
+        #[test]
+        fn specifying_prefix() {
+            let mut builder = FlatConfigBuilder::default();
+            builder.with_prefix("foo");
+
+            // the prefix is formatted along with the rest of the key
+            assert_eq!(builder.prefix, Some("foo".to_string()));
+        }
+
+        #[test]
+        fn specifying_key_format() {
+            let mut builder = FlatConfigBuilder::default();
+            assert_eq!(builder.key_format, KeyFormat::Upper);
+
+            builder.with_key_format(KeyFormat::Lower);
+
+            assert_eq!(builder.key_format, KeyFormat::Lower);
+        }
+
+        #[test]
+        fn dotted_key_format_overrides_separator() {
+            let mut builder = FlatConfigBuilder::default();
+            builder.with_key_format(KeyFormat::Dotted);
+
+            let options = builder.flatten_options();
 
-            // If we encounter another table, we just need to recurse
-            ValueKind::Table(ref table) => {
-                flatten_into_inner(table, output, separator, array_separator, components)?;
-            }
+            assert_eq!(options.separator, ".");
+            assert_eq!(options.index_separator, ".");
+        }
 
-            // Arrays are only supported if they contain primitive/str types
-            // because what does it actually mean to flatten an array into
-            // separate environment variables? We could do something like
-            // FOO_0 = "a"
-            // FOO_1 = "b"
-            // FOO_2 = "c"
-            // etc.
-            // but consider what the parser consuming said variables would have
-            // to look like? And what does it do when some arbitrary index is
-            // a complex type like an array or a map?
-            //
-            // Instead, it's simpler if we just convert the array into a
-            // sequence-separated string, which limits the kinds of things we
-            // can store in an array
-            ValueKind::Array(ref array) => {
-                let candidate = components.join(separator);
+        #[test]
+        fn specifying_invalid_key_policy() {
+            let mut builder = FlatConfigBuilder::default();
+            assert_eq!(builder.invalid_key_policy, InvalidKeyPolicy::Allow);
 
-                if output.contains_key(&candidate) {
-                    return Err(ConfpilerError::DuplicateKey(candidate));
-                }
+            builder.with_invalid_key_policy(InvalidKeyPolicy::Error);
 
-                let val = array
-                    .iter()
-                    .cloned()
-                    .map(|e| e.into_string())
-                    .collect::<std::result::Result<Vec<String>, ConfigError>>()
-                    // TODO: this is actually an assumption about why this would fail - MCL - 2022-02-21
-                    .map_err(|_| ConfpilerError::UnsupportedArray(candidate.clone()))?
-                    .join(array_separator);
-
-                output.insert(candidate, val);
-            }
+            assert_eq!(builder.invalid_key_policy, InvalidKeyPolicy::Error);
+        }
 
-            // for everything else, we want to add the key/value to the output
-            _ => {
-                let candidate = components.join(separator);
+        #[test]
+        fn specifying_interpolation() {
+            let mut builder = FlatConfigBuilder::default();
+            assert!(!builder.interpolation.keys);
+            assert!(!builder.interpolation.env);
 
-                if output.contains_key(&candidate) {
-                    return Err(ConfpilerError::DuplicateKey(candidate));
-                }
+            builder.with_interpolation(true);
+            builder.with_env_interpolation(true);
 
-                // this clone might be unnecessary and we could just convert
-                // directly into a string, but I think I want the error to be
-                // raised if the interface changes to not allow arbitrary things
-                // to be converted to string.
-                output.insert(candidate, value.clone().into_string()?);
-            }
+            assert!(builder.interpolation.keys);
+            assert!(builder.interpolation.env);
         }
 
-        // we have to remove the key we pushed
-        components.pop();
-    }
+        #[test]
+        fn specifying_null_deletes() {
+            let mut builder = FlatConfigBuilder::default();
+            assert!(!builder.null_deletes);
 
-    Ok(())
-}
+            builder.with_null_deletes(true);
 
-#[cfg(test)]
-mod tests {
-    mod flat_config {
-        use super::super::*;
+            assert!(builder.null_deletes);
+        }
 
         #[test]
-        fn builder_yields_a_default_builder() {
-            assert_eq!(FlatConfig::builder(), FlatConfigBuilder::default());
+        fn specifying_required_marker() {
+            let mut builder = FlatConfigBuilder::default();
+            assert!(builder.required_marker.is_none());
+
+            builder.with_required_marker("CHANGEME");
+
+            assert_eq!(builder.required_marker, Some("CHANGEME".to_string()));
         }
 
         #[test]
-        fn merging() {
-            let mut a = FlatConfig {
-                origin: "origin1".to_string(),
-                items: HashMap::from([
-                    ("herp".to_string(), "derp".to_string()),
-                    ("hoof".to_string(), "changeme".to_string()),
-                ]),
-            };
-            let b = FlatConfig {
-                origin: "origin2".to_string(),
-                items: HashMap::from([
-                    ("foo".to_string(), "bar".to_string()),
-                    ("hoof".to_string(), "doof".to_string()),
-                ]),
-            };
-
-            let expected = FlatConfig {
-                origin: "origin1".to_string(),
-                items: HashMap::from([
-                    ("foo".to_string(), "bar".to_string()),
-                    ("hoof".to_string(), "doof".to_string()),
-                    ("herp".to_string(), "derp".to_string()),
-                ]),
-            };
+        fn specifying_schema() {
+            let mut builder = FlatConfigBuilder::default();
+            assert!(builder.schema.is_none());
 
-            let warnings = a.merge(&b);
+            let schema = Schema::from_str("FOO: int", SourceFormat::Yaml).expect("invalid schema");
+            builder.with_schema(schema.clone());
 
-            assert_eq!(a, expected);
-            assert!(warnings.is_empty());
+            assert_eq!(builder.schema, Some(schema));
         }
 
         #[test]
-        fn merging_when_overriding_with_same_value_generates_warnings() {
-            let mut a = FlatConfig {
-                origin: "origin1".to_string(),
-                items: HashMap::from([
-                    ("herp".to_string(), "derp".to_string()),
-                    ("hoof".to_string(), "changeme".to_string()),
-                ]),
-            };
-            let b = FlatConfig {
-                origin: "origin2".to_string(),
-                items: HashMap::from([
-                    ("foo".to_string(), "bar".to_string()),
-                    ("herp".to_string(), "derp".to_string()),
-                    ("hoof".to_string(), "changeme".to_string()),
-                ]),
-            };
+        fn specifying_separator() {
+            let mut builder = FlatConfigBuilder::default();
+            builder.with_separator("*");
 
-            let expected = FlatConfig {
-                origin: "origin1".to_string(),
-                items: HashMap::from([
-                    ("foo".to_string(), "bar".to_string()),
-                    ("herp".to_string(), "derp".to_string()),
-                    ("hoof".to_string(), "changeme".to_string()),
-                ]),
-            };
+            assert_eq!(builder.separator, "*".to_string());
+        }
 
-            let warnings = a.merge(&b);
+        #[test]
+        fn specifying_new_key_policy() {
+            let mut builder = FlatConfigBuilder::default();
+            assert_eq!(builder.new_key_policy, NewKeyPolicy::Allow);
 
-            assert_eq!(a, expected);
+            builder.with_new_key_policy(NewKeyPolicy::Deny);
 
-            assert_eq!(warnings.len(), 2);
+            assert_eq!(builder.new_key_policy, NewKeyPolicy::Deny);
+        }
 
-            // we're sensitive to ordering here because of the hashing, so just
-            // assert individually
-            assert!(warnings.contains(&MergeWarning::RedundantValue {
-                overrider: "origin2".to_string(),
-                key: "herp".to_string(),
-                value: "derp".to_string(),
-            }));
+        #[test]
+        fn specifying_type_change_policy() {
+            let mut builder = FlatConfigBuilder::default();
+            assert_eq!(builder.type_change_policy, TypeChangePolicy::Warn);
 
-            assert!(warnings.contains(&MergeWarning::RedundantValue {
-                overrider: "origin2".to_string(),
-                key: "hoof".to_string(),
-                value: "changeme".to_string(),
-            }));
-        }
-    }
+            builder.with_type_change_policy(TypeChangePolicy::Deny);
 
-    mod flat_config_builder {
-        use super::super::*;
+            assert_eq!(builder.type_change_policy, TypeChangePolicy::Deny);
+        }
 
         #[test]
-        fn defaults() {
-            let builder = FlatConfigBuilder::default();
-            assert!(builder.configs.is_empty());
-            assert_eq!(builder.separator, "__".to_string());
-            assert_eq!(builder.array_separator, ",".to_string());
+        fn specifying_array_strategy() {
+            let mut builder = FlatConfigBuilder::default();
+            assert_eq!(builder.array_strategy, ArrayStrategy::Joined);
+
+            builder.with_array_strategy(ArrayStrategy::Both);
+
+            assert_eq!(builder.array_strategy, ArrayStrategy::Both);
         }
 
         #[test]
-        fn adding_configs() {
+        fn index_separator_defaults_to_separator() {
             let mut builder = FlatConfigBuilder::default();
-            builder.add_config("foo/bar");
-            builder.add_config("foo/baz");
+            builder.with_separator("*");
 
-            let expected = vec!["foo/bar".to_string(), "foo/baz".to_string()];
+            assert_eq!(builder.flatten_options().index_separator, "*");
 
-            assert_eq!(builder.configs, expected);
+            builder.with_index_separator("_");
+
+            assert_eq!(builder.flatten_options().index_separator, "_");
         }
 
         #[test]
-        fn specifying_prefix() {
+        fn specifying_array_length_suffix() {
             let mut builder = FlatConfigBuilder::default();
-            builder.with_prefix("foo");
+            builder.with_array_length_suffix("LEN");
 
-            assert_eq!(builder.prefix, Some("FOO".to_string()));
+            assert_eq!(builder.array_length_suffix, Some("LEN".to_string()));
         }
 
         #[test]
-        fn specifying_separator() {
+        fn specifying_json_encoding() {
             let mut builder = FlatConfigBuilder::default();
-            builder.with_separator("*");
+            assert!(!builder.json_arrays);
 
-            assert_eq!(builder.separator, "*".to_string());
+            builder.with_json_arrays(true);
+            builder.with_json_key("FOO");
+            builder.with_json_key("BAR__BAZ");
+
+            assert!(builder.json_arrays);
+            assert_eq!(
+                builder.json_keys,
+                vec!["FOO".to_string(), "BAR__BAZ".to_string()]
+            );
         }
 
         #[test]
@@ -578,9 +2273,9 @@ mod tests {
         // so this is a PITA to create, but it's probably? Better than trying
         // to load a real config file from disk. And I have more control over
         // the types
-        fn valid_input() -> HashMap<String, Value> {
+        fn valid_input() -> Map<String, Value> {
             let origin = "test".to_string();
-            let input = HashMap::from([
+            Map::from([
                 (
                     "foo".to_string(),
                     Value::new(Some(&origin), ValueKind::Float(10.2)),
@@ -593,7 +2288,7 @@ mod tests {
                     "baz".to_string(),
                     Value::new(
                         Some(&origin),
-                        ValueKind::Table(HashMap::from([
+                        ValueKind::Table(Map::from([
                             (
                                 "herp".to_string(),
                                 Value::new(Some(&origin), ValueKind::Boolean(false)),
@@ -606,7 +2301,7 @@ mod tests {
                                 "hoof".to_string(),
                                 Value::new(
                                     Some(&origin),
-                                    ValueKind::Table(HashMap::from([(
+                                    ValueKind::Table(Map::from([(
                                         "doof".to_string(),
                                         Value::new(Some(&origin), ValueKind::I64(999)),
                                     )])),
@@ -626,28 +2321,26 @@ mod tests {
                         ]),
                     ),
                 ),
-            ]);
-
-            input
+            ])
         }
 
         #[test]
         fn accepts_empty_input() {
-            let mut out = HashMap::new();
-            let input = HashMap::new();
+            let mut out = Flattened::default();
+            let input = Map::new();
 
-            let res = flatten_into(&input, &mut out, None, "__", ",");
+            let res = flatten_into(&input, &mut out, &FlattenOptions::default());
 
             assert!(res.is_ok());
-            assert!(out.is_empty());
+            assert!(out.items.is_empty());
         }
 
         #[test]
         fn flattens_valid_input() {
-            let mut out = HashMap::new();
+            let mut out = Flattened::default();
             let input = valid_input();
 
-            let expected: HashMap<String, String> = HashMap::from([
+            let expected: IndexMap<String, String> = IndexMap::from([
                 ("FOO".to_string(), "10.2".to_string()),
                 ("BAR".to_string(), "Hello".to_string()),
                 ("BAZ__HERP".to_string(), "false".to_string()),
@@ -656,18 +2349,42 @@ mod tests {
                 ("BIZ".to_string(), "false,1111,Goodbye".to_string()),
             ]);
 
-            let res = flatten_into(&input, &mut out, None, "__", ",");
+            let res = flatten_into(&input, &mut out, &FlattenOptions::default());
+
+            assert!(res.is_ok());
+            // IndexMap equality ignores order, so compare the sequences
+            assert_eq!(
+                out.items.iter().collect::<Vec<_>>(),
+                expected.iter().collect::<Vec<_>>()
+            );
+        }
+
+        #[test]
+        fn records_value_types() {
+            let mut out = Flattened::default();
+            let input = valid_input();
+
+            let expected = HashMap::from([
+                ("FOO".to_string(), ValueType::Float),
+                ("BAR".to_string(), ValueType::String),
+                ("BAZ__HERP".to_string(), ValueType::Boolean),
+                ("BAZ__DERP".to_string(), ValueType::Integer),
+                ("BAZ__HOOF__DOOF".to_string(), ValueType::Integer),
+                ("BIZ".to_string(), ValueType::Array),
+            ]);
+
+            let res = flatten_into(&input, &mut out, &FlattenOptions::default());
 
             assert!(res.is_ok());
-            assert_eq!(out, expected);
+            assert_eq!(out.kinds, expected);
         }
 
         #[test]
         fn supports_prefixing() {
-            let mut out = HashMap::new();
+            let mut out = Flattened::default();
             let input = valid_input();
 
-            let expected: HashMap<String, String> = HashMap::from([
+            let expected: IndexMap<String, String> = IndexMap::from([
                 ("PRE__FOO".to_string(), "10.2".to_string()),
                 ("PRE__BAR".to_string(), "Hello".to_string()),
                 ("PRE__BAZ__HERP".to_string(), "false".to_string()),
@@ -676,20 +2393,23 @@ mod tests {
                 ("PRE__BIZ".to_string(), "false,1111,Goodbye".to_string()),
             ]);
 
-            let prefix = Some("PRE".to_string());
+            let options = FlattenOptions {
+                prefix: Some("pre"),
+                ..FlattenOptions::default()
+            };
 
-            let res = flatten_into(&input, &mut out, prefix.as_ref(), "__", ",");
+            let res = flatten_into(&input, &mut out, &options);
 
             assert!(res.is_ok());
-            assert_eq!(out, expected);
+            assert_eq!(out.items, expected);
         }
 
         #[test]
         fn uses_the_specified_separators() {
-            let mut out = HashMap::new();
+            let mut out = Flattened::default();
             let input = valid_input();
 
-            let expected: HashMap<String, String> = HashMap::from([
+            let expected: IndexMap<String, String> = IndexMap::from([
                 ("FOO".to_string(), "10.2".to_string()),
                 ("BAR".to_string(), "Hello".to_string()),
                 ("BAZ*HERP".to_string(), "false".to_string()),
@@ -698,15 +2418,269 @@ mod tests {
                 ("BIZ".to_string(), "false 1111 Goodbye".to_string()),
             ]);
 
-            let res = flatten_into(&input, &mut out, None, "*", " ");
+            let options = FlattenOptions {
+                separator: "*",
+                array_separator: " ",
+                ..FlattenOptions::default()
+            };
+
+            let res = flatten_into(&input, &mut out, &options);
+
+            assert!(res.is_ok());
+            assert_eq!(out.items, expected);
+        }
+
+        #[test]
+        fn uses_the_specified_key_format() {
+            let mut out = Flattened::default();
+            let input = valid_input();
+
+            let expected: IndexMap<String, String> = IndexMap::from([
+                ("app.foo".to_string(), "10.2".to_string()),
+                ("app.bar".to_string(), "Hello".to_string()),
+                ("app.baz.herp".to_string(), "false".to_string()),
+                ("app.baz.derp".to_string(), "15".to_string()),
+                ("app.baz.hoof.doof".to_string(), "999".to_string()),
+                ("app.biz".to_string(), "false,1111,Goodbye".to_string()),
+            ]);
+
+            let options = FlattenOptions {
+                prefix: Some("App"),
+                key_format: KeyFormat::Dotted,
+                separator: ".",
+                ..FlattenOptions::default()
+            };
+
+            let res = flatten_into(&input, &mut out, &options);
+
+            assert!(res.is_ok());
+            assert_eq!(out.items, expected);
+        }
+
+        #[test]
+        fn sanitizes_invalid_keys() {
+            let mut out = Flattened::default();
+            let input = valid_input();
+
+            let options = FlattenOptions {
+                prefix: Some("my-app"),
+                separator: ".",
+                index_separator: ".",
+                array_strategy: ArrayStrategy::Both,
+                invalid_key_policy: InvalidKeyPolicy::Sanitize,
+                ..FlattenOptions::default()
+            };
+
+            let res = flatten_into(&input, &mut out, &options);
+
+            assert!(res.is_ok());
+            assert!(out.items.keys().all(|k| is_valid_env_name(k)));
+            assert_eq!(
+                out.items.get("MY_APP_BAZ_HOOF_DOOF"),
+                Some(&"999".to_string())
+            );
+            assert_eq!(out.items.get("MY_APP_BIZ_0"), Some(&"false".to_string()));
+            assert_eq!(
+                out.arrays.get("MY_APP_BIZ"),
+                Some(&vec![
+                    "MY_APP_BIZ_0".to_string(),
+                    "MY_APP_BIZ_1".to_string(),
+                    "MY_APP_BIZ_2".to_string(),
+                ])
+            );
+        }
+
+        #[test]
+        fn errors_on_invalid_keys() {
+            let mut out = Flattened::default();
+            let input = valid_input();
+
+            let options = FlattenOptions {
+                origin: "some/config",
+                index_separator: "-",
+                array_strategy: ArrayStrategy::Indexed,
+                invalid_key_policy: InvalidKeyPolicy::Error,
+                ..FlattenOptions::default()
+            };
+
+            let res = flatten_into(&input, &mut out, &options);
+
+            match res {
+                Err(ConfpilerError::InvalidKey {
+                    key,
+                    source_key,
+                    origin,
+                }) => {
+                    assert_eq!(key, "BIZ-0");
+                    assert_eq!(source_key, "biz");
+                    assert_eq!(origin, "some/config");
+                }
+                _ => panic!("expected an invalid key error"),
+            }
+        }
+
+        #[test]
+        fn records_nulls_when_they_delete_keys() {
+            let mut input = valid_input();
+            input.insert(
+                "nothing".to_string(),
+                Value::new(Some(&"test".to_string()), ValueKind::Nil),
+            );
+
+            let mut out = Flattened::default();
+            let res = flatten_into(&input, &mut out, &FlattenOptions::default());
+
+            assert!(res.is_ok());
+            assert!(out.nulls.is_empty());
+
+            let options = FlattenOptions {
+                null_deletes: true,
+                ..FlattenOptions::default()
+            };
+
+            let mut out = Flattened::default();
+            let res = flatten_into(&input, &mut out, &options);
+
+            assert!(res.is_ok());
+            assert_eq!(out.nulls, vec!["NOTHING".to_string()]);
+            assert!(!out.items.contains_key("NOTHING"));
+        }
+
+        #[test]
+        fn supports_indexed_arrays() {
+            let mut out = Flattened::default();
+            let input = valid_input();
+
+            let expected: IndexMap<String, String> = IndexMap::from([
+                ("FOO".to_string(), "10.2".to_string()),
+                ("BAR".to_string(), "Hello".to_string()),
+                ("BAZ__HERP".to_string(), "false".to_string()),
+                ("BAZ__DERP".to_string(), "15".to_string()),
+                ("BAZ__HOOF__DOOF".to_string(), "999".to_string()),
+                ("BIZ_0".to_string(), "false".to_string()),
+                ("BIZ_1".to_string(), "1111".to_string()),
+                ("BIZ_2".to_string(), "Goodbye".to_string()),
+                ("BIZ_LEN".to_string(), "3".to_string()),
+            ]);
+
+            let options = FlattenOptions {
+                array_strategy: ArrayStrategy::Indexed,
+                index_separator: "_",
+                array_length_suffix: Some("LEN"),
+                ..FlattenOptions::default()
+            };
+
+            let res = flatten_into(&input, &mut out, &options);
+
+            assert!(res.is_ok());
+            assert_eq!(out.items, expected);
+            assert_eq!(out.kinds.get("BIZ_0"), Some(&ValueType::Boolean));
+            assert_eq!(out.kinds.get("BIZ_1"), Some(&ValueType::Integer));
+            assert_eq!(out.kinds.get("BIZ_LEN"), Some(&ValueType::Integer));
+            assert_eq!(
+                out.arrays,
+                HashMap::from([(
+                    "BIZ".to_string(),
+                    vec![
+                        "BIZ_0".to_string(),
+                        "BIZ_1".to_string(),
+                        "BIZ_2".to_string(),
+                        "BIZ_LEN".to_string(),
+                    ]
+                )])
+            );
+        }
+
+        #[test]
+        fn supports_joined_and_indexed_arrays() {
+            let mut out = Flattened::default();
+            let input = valid_input();
+
+            let options = FlattenOptions {
+                array_strategy: ArrayStrategy::Both,
+                ..FlattenOptions::default()
+            };
+
+            let res = flatten_into(&input, &mut out, &options);
+
+            assert!(res.is_ok());
+            assert_eq!(
+                out.items.get("BIZ"),
+                Some(&"false,1111,Goodbye".to_string())
+            );
+            assert_eq!(out.items.get("BIZ__0"), Some(&"false".to_string()));
+            assert_eq!(out.items.get("BIZ__2"), Some(&"Goodbye".to_string()));
+            assert!(!out.items.contains_key("BIZ__LEN"));
+        }
+
+        #[test]
+        fn supports_json_arrays() {
+            let mut out = Flattened::default();
+            let origin = "test".to_string();
+            let mut input = valid_input();
+            input.insert(
+                "biz".to_string(),
+                Value::new(
+                    Some(&origin),
+                    ValueKind::Array(vec![
+                        Value::new(Some(&origin), ValueKind::Boolean(false)),
+                        Value::new(
+                            Some(&origin),
+                            ValueKind::Table(Map::from([(
+                                "host".to_string(),
+                                Value::new(Some(&origin), ValueKind::String("a".to_string())),
+                            )])),
+                        ),
+                    ]),
+                ),
+            );
+
+            let options = FlattenOptions {
+                json_arrays: true,
+                array_strategy: ArrayStrategy::Indexed,
+                ..FlattenOptions::default()
+            };
+
+            let res = flatten_into(&input, &mut out, &options);
+
+            assert!(res.is_ok());
+            assert_eq!(
+                out.items.get("BIZ"),
+                Some(&r#"[false,{"host":"a"}]"#.to_string())
+            );
+            assert_eq!(out.kinds.get("BIZ"), Some(&ValueType::Array));
+            assert!(!out.items.contains_key("BIZ__0"));
+        }
+
+        #[test]
+        fn supports_json_keys() {
+            let mut out = Flattened::default();
+            let input = valid_input();
+            let json_keys = vec!["BAZ__HOOF".to_string(), "BIZ".to_string()];
+
+            let options = FlattenOptions {
+                json_keys: &json_keys,
+                ..FlattenOptions::default()
+            };
+
+            let res = flatten_into(&input, &mut out, &options);
 
             assert!(res.is_ok());
-            assert_eq!(out, expected);
+            assert_eq!(
+                out.items.get("BAZ__HOOF"),
+                Some(&r#"{"doof":999}"#.to_string())
+            );
+            assert_eq!(out.kinds.get("BAZ__HOOF"), Some(&ValueType::Table));
+            assert!(!out.items.contains_key("BAZ__HOOF__DOOF"));
+            assert_eq!(
+                out.items.get("BIZ"),
+                Some(&r#"[false,1111,"Goodbye"]"#.to_string())
+            );
         }
 
         #[test]
         fn errors_on_duplicate_keys() {
-            let mut out = HashMap::new();
+            let mut out = Flattened::default();
             let valid = valid_input();
 
             let mut invalid = valid.clone();
@@ -715,7 +2689,7 @@ mod tests {
                 Value::new(Some(&"test".to_string()), ValueKind::Float(1.0)),
             );
 
-            let res = flatten_into(&invalid, &mut out, None, "__", ",");
+            let res = flatten_into(&invalid, &mut out, &FlattenOptions::default());
 
             assert!(res.is_err());
 
@@ -731,8 +2705,8 @@ mod tests {
                 Value::new(Some(&"test".to_string()), ValueKind::Boolean(true)),
             );
 
-            let mut out = HashMap::new();
-            let res = flatten_into(&invalid, &mut out, None, "__", ",");
+            let mut out = Flattened::default();
+            let res = flatten_into(&invalid, &mut out, &FlattenOptions::default());
 
             assert!(res.is_err());
 
@@ -744,7 +2718,7 @@ mod tests {
 
         #[test]
         fn errors_on_unsupported_array() {
-            let mut out = HashMap::new();
+            let mut out = Flattened::default();
             let valid = valid_input();
 
             let origin = "test".to_string();
@@ -755,13 +2729,13 @@ mod tests {
                     Some(&"test".to_string()),
                     ValueKind::Array(vec![
                         Value::new(Some(&origin), ValueKind::Boolean(false)),
-                        Value::new(Some(&origin), ValueKind::Table(HashMap::new())),
+                        Value::new(Some(&origin), ValueKind::Table(Map::new())),
                         Value::new(Some(&origin), ValueKind::String("Goodbye".to_string())),
                     ]),
                 ),
             );
 
-            let res = flatten_into(&invalid, &mut out, None, "__", ",");
+            let res = flatten_into(&invalid, &mut out, &FlattenOptions::default());
 
             assert!(res.is_err());
 
@@ -769,6 +2743,19 @@ mod tests {
                 ConfpilerError::UnsupportedArray(key) => assert_eq!(key, "BIZ".to_string()),
                 e => panic!("unexpected error variant: {}", e),
             };
+
+            // indexing doesn't change what's supported
+            let mut out = Flattened::default();
+            let options = FlattenOptions {
+                array_strategy: ArrayStrategy::Indexed,
+                ..FlattenOptions::default()
+            };
+            let res = flatten_into(&invalid, &mut out, &options);
+
+            match res.unwrap_err() {
+                ConfpilerError::UnsupportedArray(key) => assert_eq!(key, "BIZ".to_string()),
+                e => panic!("unexpected error variant: {}", e),
+            };
         }
     }
 }
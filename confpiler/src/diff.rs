@@ -0,0 +1,196 @@
+use std::fmt;
+
+use crate::config::FlatConfig;
+
+/// A single difference between two [FlatConfig]s, as produced by
+/// [FlatConfig::diff].
+///
+/// Origins are the configs that set the final value of a key, and are `None`
+/// when that is unknown (like for a [FlatConfig::default]).
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub enum KeyDiff {
+    /// The key only exists in the new config.
+    Added {
+        key: String,
+        value: String,
+        origin: Option<String>,
+    },
+
+    /// The key only exists in the old config.
+    Removed {
+        key: String,
+        value: String,
+        origin: Option<String>,
+    },
+
+    /// The key exists in both configs, but with different values.
+    Changed {
+        key: String,
+        old_value: String,
+        new_value: String,
+        old_origin: Option<String>,
+        new_origin: Option<String>,
+    },
+}
+
+impl KeyDiff {
+    /// The key that differs.
+    pub fn key(&self) -> &str {
+        match self {
+            Self::Added { key, .. } | Self::Removed { key, .. } | Self::Changed { key, .. } => key,
+        }
+    }
+}
+
+impl fmt::Display for KeyDiff {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Added {
+                ref key, ref value, ..
+            } => {
+                write!(f, "'{key}' was added with '{value}'")
+            }
+            Self::Removed {
+                ref key, ref value, ..
+            } => {
+                write!(f, "'{key}' was removed, previously '{value}'")
+            }
+            Self::Changed {
+                ref key,
+                ref old_value,
+                ref new_value,
+                ..
+            } => {
+                write!(f, "'{key}' was changed from '{old_value}' to '{new_value}'")
+            }
+        }
+    }
+}
+
+/// Compare `old` to `new`.
+///
+/// Removed and changed keys are listed in the order they appear in `old`,
+/// followed by added keys in the order they appear in `new`.
+pub(crate) fn diff(old: &FlatConfig, new: &FlatConfig) -> Vec<KeyDiff> {
+    let origin = |conf: &FlatConfig, key: &str| conf.origin_of(key).map(|o| o.to_string());
+
+    let mut out = Vec::new();
+
+    for (key, old_value) in old.items().iter() {
+        match new.items().get(key) {
+            None => out.push(KeyDiff::Removed {
+                key: key.clone(),
+                value: old_value.clone(),
+                origin: origin(old, key),
+            }),
+            Some(new_value) if new_value != old_value => out.push(KeyDiff::Changed {
+                key: key.clone(),
+                old_value: old_value.clone(),
+                new_value: new_value.clone(),
+                old_origin: origin(old, key),
+                new_origin: origin(new, key),
+            }),
+            Some(_) => {}
+        }
+    }
+
+    for (key, value) in new.items().iter() {
+        if !old.items().contains_key(key) {
+            out.push(KeyDiff::Added {
+                key: key.clone(),
+                value: value.clone(),
+                origin: origin(new, key),
+            });
+        }
+    }
+
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    mod diff {
+        use super::super::*;
+        use crate::SourceFormat;
+
+        fn config(origin: &str, content: &str) -> FlatConfig {
+            FlatConfig::builder()
+                .add_config_str(origin, content, SourceFormat::Dotenv)
+                .build()
+                .expect("failed to build config")
+                .0
+        }
+
+        #[test]
+        fn listing_differences() {
+            let old = config("old", "SAME=1\nGONE=2\nCHANGED=3\n");
+            let new = config("new", "NEW=4\nCHANGED=5\nSAME=1\n");
+
+            let expected = vec![
+                KeyDiff::Removed {
+                    key: "GONE".to_string(),
+                    value: "2".to_string(),
+                    origin: Some("old".to_string()),
+                },
+                KeyDiff::Changed {
+                    key: "CHANGED".to_string(),
+                    old_value: "3".to_string(),
+                    new_value: "5".to_string(),
+                    old_origin: Some("old".to_string()),
+                    new_origin: Some("new".to_string()),
+                },
+                KeyDiff::Added {
+                    key: "NEW".to_string(),
+                    value: "4".to_string(),
+                    origin: Some("new".to_string()),
+                },
+            ];
+
+            assert_eq!(old.diff(&new), expected);
+
+            let keys = expected.iter().map(|d| d.key()).collect::<Vec<_>>();
+            assert_eq!(keys, vec!["GONE", "CHANGED", "NEW"]);
+        }
+
+        #[test]
+        fn identical_configs() {
+            let old = config("old", "FOO=1\n");
+            let new = config("new", "FOO=1\n");
+
+            assert!(old.diff(&new).is_empty());
+            assert!(old.diff(&old).is_empty());
+        }
+
+        #[test]
+        fn comparing_to_an_empty_config() {
+            let new = config("new", "FOO=1\n");
+
+            assert_eq!(
+                new.diff(&FlatConfig::default()),
+                vec![KeyDiff::Removed {
+                    key: "FOO".to_string(),
+                    value: "1".to_string(),
+                    origin: Some("new".to_string()),
+                }]
+            );
+
+            assert_eq!(
+                FlatConfig::default().diff(&FlatConfig::default()),
+                Vec::new()
+            );
+        }
+
+        #[test]
+        fn display() {
+            let diff = KeyDiff::Changed {
+                key: "FOO".to_string(),
+                old_value: "1".to_string(),
+                new_value: "2".to_string(),
+                old_origin: None,
+                new_origin: None,
+            };
+
+            assert_eq!(diff.to_string(), "'FOO' was changed from '1' to '2'");
+        }
+    }
+}
@@ -0,0 +1,384 @@
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+
+use crate::config::{FlattenOptions, Flattened};
+use crate::error::{ConfpilerError, Result};
+use crate::provenance::ValueType;
+
+/// The extensions `config-rs` tries when a config is specified without one.
+pub(crate) const CONFIG_EXTENSIONS: &[&str] =
+    &["toml", "json", "yaml", "yml", "ini", "ron", "json5"];
+
+/// Determine if the given config path refers to a dotenv file, returning the
+/// path to that file if it does.
+///
+/// Dotenv files are files named `.env` or `.env.<something>`, and files with a
+/// `.env` extension. Like other formats, a dotenv file may be specified
+/// without its extension (`foo/legacy` for `foo/legacy.env`), but only if no
+/// other config matches, since neither could be said to take precedence.
+pub(crate) fn dotenv_path(conf_path: &str) -> Result<Option<PathBuf>> {
+    let path = Path::new(conf_path);
+
+    if path.is_file() {
+        let is_dotenv = path
+            .file_name()
+            .and_then(|n| n.to_str())
+            .map_or(false, |name| {
+                name == ".env"
+                    || name.starts_with(".env.")
+                    || path.extension().map_or(false, |ext| ext == "env")
+            });
+
+        return Ok(is_dotenv.then(|| path.to_path_buf()));
+    }
+
+    let candidate = PathBuf::from(format!("{conf_path}.env"));
+    if !candidate.is_file() {
+        return Ok(None);
+    }
+
+    let mut candidates = CONFIG_EXTENSIONS
+        .iter()
+        .map(|ext| format!("{conf_path}.{ext}"))
+        .filter(|p| Path::new(p).is_file())
+        .collect::<Vec<_>>();
+
+    if !candidates.is_empty() {
+        candidates.insert(0, candidate.display().to_string());
+        return Err(ConfpilerError::AmbiguousConfig {
+            path: conf_path.to_string(),
+            candidates,
+        });
+    }
+
+    Ok(Some(candidate))
+}
+
+/// Parse the contents of a dotenv file into `output`.
+///
+/// Keys are considered to already be flattened, so they are not formatted or
+/// prefixed, but they are still subject to the
+/// [InvalidKeyPolicy](crate::InvalidKeyPolicy). Like a shell sourcing the
+/// file, a key that is defined more than once takes its last value, but
+/// different keys that are sanitized into the same key are still an error.
+pub(crate) fn parse_into(
+    input: &str,
+    output: &mut Flattened,
+    options: &FlattenOptions,
+) -> Result<()> {
+    let mut parser = Parser {
+        chars: input.chars().peekable(),
+        line: 1,
+    };
+    // the key each output key was generated from
+    let mut source_keys: HashMap<String, String> = HashMap::new();
+
+    while let Some((key, value)) =
+        parser
+            .next_entry()
+            .map_err(|reason| ConfpilerError::InvalidDotenv {
+                origin: options.origin.to_string(),
+                line: parser.line,
+                reason,
+            })?
+    {
+        let value_type = ValueType::infer(&value);
+        let source_key = key;
+        let key = options.checked_key(source_key.clone(), &source_key)?;
+
+        match source_keys.get(&key) {
+            Some(previous) if *previous != source_key => {
+                return Err(ConfpilerError::DuplicateKey(key));
+            }
+            Some(_) => {}
+            None => {
+                source_keys.insert(key.clone(), source_key);
+            }
+        }
+
+        output.kinds.insert(key.clone(), value_type);
+        output.items.insert(key, value);
+    }
+
+    Ok(())
+}
+
+struct Parser<'a> {
+    chars: std::iter::Peekable<std::str::Chars<'a>>,
+    line: usize,
+}
+
+impl Parser<'_> {
+    fn next_entry(&mut self) -> std::result::Result<Option<(String, String)>, String> {
+        loop {
+            self.skip_inline_whitespace();
+
+            match self.chars.peek() {
+                None => return Ok(None),
+                Some('\n') => {
+                    self.bump();
+                }
+                Some('#') => self.skip_line(),
+                Some(_) => break,
+            }
+        }
+
+        let mut key = self.read_key();
+        if key == "export" && self.chars.peek().map_or(false, |c| c.is_whitespace()) {
+            self.skip_inline_whitespace();
+            key = self.read_key();
+        }
+
+        self.skip_inline_whitespace();
+
+        if key.is_empty() {
+            return Err("expected a key".to_string());
+        }
+
+        if self.chars.peek() != Some(&'=') {
+            return Err(format!("expected '=' after \"{key}\""));
+        }
+        self.bump();
+
+        self.skip_inline_whitespace();
+
+        let value = match self.chars.peek() {
+            Some('\'') => self.read_quoted('\'')?,
+            Some('"') => self.read_quoted('"')?,
+            _ => self.read_unquoted(),
+        };
+
+        // only whitespace and comments may follow a value
+        self.skip_inline_whitespace();
+        match self.chars.peek() {
+            None | Some('\n') => {}
+            Some('#') => self.skip_line(),
+            Some(c) => return Err(format!("unexpected '{c}' after the value of \"{key}\"")),
+        }
+
+        Ok(Some((key, value)))
+    }
+
+    fn bump(&mut self) -> Option<char> {
+        let c = self.chars.next();
+        if c == Some('\n') {
+            self.line += 1;
+        }
+        c
+    }
+
+    fn skip_inline_whitespace(&mut self) {
+        while self
+            .chars
+            .peek()
+            .map_or(false, |c| *c != '\n' && c.is_whitespace())
+        {
+            self.bump();
+        }
+    }
+
+    fn skip_line(&mut self) {
+        while self.chars.peek().map_or(false, |c| *c != '\n') {
+            self.bump();
+        }
+    }
+
+    fn read_key(&mut self) -> String {
+        let mut key = String::new();
+
+        while let Some(c) = self
+            .chars
+            .peek()
+            .filter(|c| !c.is_whitespace() && **c != '=' && **c != '#')
+        {
+            key.push(*c);
+            self.bump();
+        }
+
+        key
+    }
+
+    fn read_unquoted(&mut self) -> String {
+        let mut value = String::new();
+
+        while let Some(c) = self.chars.peek().filter(|c| **c != '\n') {
+            // a comment has to be preceded by whitespace, so that things like
+            // url fragments are preserved
+            if *c == '#' && value.ends_with(char::is_whitespace) {
+                break;
+            }
+
+            value.push(*c);
+            self.bump();
+        }
+
+        value.trim_end().to_string()
+    }
+
+    /// Read a (potentially multi-line) quoted value. Escapes are only
+    /// processed in double-quoted values.
+    fn read_quoted(&mut self, quote: char) -> std::result::Result<String, String> {
+        let start = self.line;
+        let mut value = String::new();
+
+        // the opening quote
+        self.bump();
+
+        loop {
+            match self.bump() {
+                None => return Err(format!("unterminated quote starting on line {start}")),
+                Some(c) if c == quote => return Ok(value),
+                Some('\\') if quote == '"' => match self.bump() {
+                    Some('n') => value.push('\n'),
+                    Some('r') => value.push('\r'),
+                    Some('t') => value.push('\t'),
+                    Some(c @ ('"' | '\\' | '$')) => value.push(c),
+                    Some(c) => {
+                        value.push('\\');
+                        value.push(c);
+                    }
+                    None => return Err(format!("unterminated quote starting on line {start}")),
+                },
+                Some(c) => value.push(c),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    mod parse_into {
+        use super::super::*;
+        use crate::keys::InvalidKeyPolicy;
+        use indexmap::IndexMap;
+
+        fn parse(input: &str) -> Result<IndexMap<String, String>> {
+            let mut out = Flattened::default();
+            parse_into(
+                input,
+                &mut out,
+                &FlattenOptions {
+                    origin: "test.env",
+                    ..FlattenOptions::default()
+                },
+            )?;
+            Ok(out.items)
+        }
+
+        fn expected(pairs: &[(&str, &str)]) -> IndexMap<String, String> {
+            pairs
+                .iter()
+                .map(|(k, v)| (k.to_string(), v.to_string()))
+                .collect()
+        }
+
+        #[test]
+        fn parses_simple_values() {
+            let input = "
+# a comment
+FOO=bar
+  BAZ = some value   # trailing comment
+export EXPORTED=yes
+EMPTY=
+URL=https://foo.bar/#fragment
+";
+
+            let res = parse(input).expect("failed to parse");
+
+            assert_eq!(
+                res,
+                expected(&[
+                    ("FOO", "bar"),
+                    ("BAZ", "some value"),
+                    ("EXPORTED", "yes"),
+                    ("EMPTY", ""),
+                    ("URL", "https://foo.bar/#fragment"),
+                ])
+            );
+        }
+
+        #[test]
+        fn parses_quoted_values() {
+            let input = r#"
+SINGLE='no \n escapes # here'
+DOUBLE="tab\there \"quoted\" \$HOME \\ \q"
+MULTI="line one
+line two"
+SINGLE_MULTI='a
+b' # comment
+"#;
+
+            let res = parse(input).expect("failed to parse");
+
+            assert_eq!(
+                res,
+                expected(&[
+                    ("SINGLE", "no \\n escapes # here"),
+                    ("DOUBLE", "tab\there \"quoted\" $HOME \\ \\q"),
+                    ("MULTI", "line one\nline two"),
+                    ("SINGLE_MULTI", "a\nb"),
+                ])
+            );
+        }
+
+        #[test]
+        fn infers_value_types() {
+            let mut out = Flattened::default();
+            parse_into(
+                "PORT=5432\nDEBUG=\"false\"\nRATIO=0.5\nPADDED=007\n",
+                &mut out,
+                &FlattenOptions::default(),
+            )
+            .expect("failed to parse");
+
+            assert_eq!(out.kinds.get("PORT"), Some(&ValueType::Integer));
+            assert_eq!(out.kinds.get("DEBUG"), Some(&ValueType::Boolean));
+            assert_eq!(out.kinds.get("RATIO"), Some(&ValueType::Float));
+            assert_eq!(out.kinds.get("PADDED"), Some(&ValueType::String));
+        }
+
+        #[test]
+        fn reports_errors_with_line_numbers() {
+            let cases = [
+                ("FOO=bar\nBAZ\n", 2),
+                ("FOO=bar\n=baz\n", 2),
+                ("FOO=\"bar\nbaz\n", 3),
+                ("FOO='bar' baz\n", 1),
+            ];
+
+            for (input, expected_line) in cases {
+                match parse(input) {
+                    Err(ConfpilerError::InvalidDotenv { origin, line, .. }) => {
+                        assert_eq!(origin, "test.env");
+                        assert_eq!(line, expected_line, "{input}");
+                    }
+                    _ => panic!("expected an error for {input}"),
+                }
+            }
+        }
+
+        #[test]
+        fn duplicate_keys_take_the_last_value() {
+            let res = parse("FOO=1\nBAR=2\nFOO=3\n").expect("failed to parse");
+            assert_eq!(res, expected(&[("FOO", "3"), ("BAR", "2")]));
+        }
+
+        #[test]
+        fn sanitized_duplicate_keys_are_errors() {
+            let mut out = Flattened::default();
+            let options = FlattenOptions {
+                origin: "test.env",
+                invalid_key_policy: InvalidKeyPolicy::Sanitize,
+                ..FlattenOptions::default()
+            };
+
+            // the same key is still allowed more than once
+            parse_into("FOO-BAR=1\nFOO-BAR=2\n", &mut out, &options).expect("failed to parse");
+            assert_eq!(out.items, expected(&[("FOO_BAR", "2")]));
+
+            let mut out = Flattened::default();
+            let res = parse_into("FOO-BAR=1\nFOO.BAR=2\n", &mut out, &options);
+            assert!(matches!(res, Err(ConfpilerError::DuplicateKey(k)) if k == "FOO_BAR"));
+        }
+    }
+}
@@ -1,7 +1,7 @@
 //! Crate error definitions and associated conversions
 use config::ConfigError;
 
-use crate::MergeWarning;
+use crate::{MergeWarning, SchemaViolation};
 
 /// Convenience alias for Results returned by this crate
 pub type Result<T> = std::result::Result<T, ConfpilerError>;
@@ -18,15 +18,119 @@ pub enum ConfpilerError {
     /// Indicates a config file would result in duplicated flattened keys.
     DuplicateKey(String),
 
+    /// Indicates a generated key is not a valid environment variable name and
+    /// the [InvalidKeyPolicy](crate::InvalidKeyPolicy) is
+    /// [Error](crate::InvalidKeyPolicy::Error).
+    InvalidKey {
+        /// The generated key.
+        key: String,
+        /// The key, as it appears in the config.
+        source_key: String,
+        /// The config containing the key.
+        origin: String,
+    },
+
+    /// Indicates a chain of `${KEY}` references that refer back to
+    /// themselves, with the first and last elements being the same key.
+    ReferenceCycle(Vec<String>),
+
+    /// Indicates a `${KEY}` reference to a key that does not exist.
+    ///
+    /// The chain of references that led to the missing key ends with the
+    /// missing key.
+    UnresolvedReference(Vec<String>),
+
+    /// Indicates a `${env:VAR}` reference to an environment variable that is
+    /// not set, and no default was provided.
+    MissingEnvVar {
+        /// The environment variable.
+        var: String,
+        /// The key whose value contains the reference.
+        key: String,
+        /// The message provided via `${env:VAR:?message}`, if any.
+        message: Option<String>,
+    },
+
+    /// Indicates keys that still have the placeholder value specified via
+    /// [FlatConfigBuilder::with_required_marker](crate::FlatConfigBuilder::with_required_marker)
+    /// after all configs were merged.
+    UnresolvedPlaceholders(Vec<String>),
+
+    /// Indicates a dotenv file could not be parsed.
+    InvalidDotenv {
+        /// The config containing the error.
+        origin: String,
+        /// The line on which the error was detected.
+        line: usize,
+        /// What was wrong.
+        reason: String,
+    },
+
+    /// Indicates a config specified without an extension matches both a
+    /// dotenv file and a config in another format.
+    AmbiguousConfig {
+        /// The config, as specified.
+        path: String,
+        /// The files it matches.
+        candidates: Vec<String>,
+    },
+
+    /// Indicates a path added via
+    /// [FlatConfigBuilder::add_directory](crate::FlatConfigBuilder::add_directory)
+    /// is not a directory.
+    DirectoryNotFound(String),
+
+    /// Indicates a directory does not contain a default config.
+    MissingDefault {
+        /// The directory.
+        directory: String,
+        /// The basename of the default config.
+        default: String,
+    },
+
+    /// Indicates environments whose declared parents refer back to
+    /// themselves, with the first and last elements being the same
+    /// environment.
+    EnvironmentCycle(Vec<String>),
+
+    /// Indicates an environment has no config in any directory, and
+    /// [FlatConfigBuilder::with_require_environments](crate::FlatConfigBuilder::with_require_environments)
+    /// was set.
+    UnknownEnvironment {
+        /// The environment.
+        environment: String,
+        /// The environments with a config in at least one directory.
+        available: Vec<String>,
+        /// The available environment closest to the given one, if any are
+        /// close enough to suggest.
+        suggestion: Option<String>,
+    },
+
     /// Indicates no config files specified when building a
     /// [FlatConfig](crate::FlatConfig).
     NoConfigSpecified,
 
     /// Indicates a config contains an array that is unsupported.
     ///
-    /// An unsupported array contains nested values.
+    /// An unsupported array contains nested values and was not configured to be
+    /// encoded as JSON.
     UnsupportedArray(String),
 
+    /// Indicates an [Unflattener](crate::Unflattener) was unable to render a
+    /// document.
+    SerializeError(String),
+
+    /// Indicates a key is both a value and the parent of other keys when
+    /// unflattening (like `FOO` and `FOO__BAR`).
+    UnflattenConflict(String),
+
+    /// Indicates a [Schema](crate::Schema) could not be loaded.
+    InvalidSchema(String),
+
+    /// Indicates a config does not match the [Schema](crate::Schema) given
+    /// to [FlatConfigBuilder::with_schema](crate::FlatConfigBuilder::with_schema).
+    SchemaViolations(Vec<SchemaViolation>),
+
     /// This is a convenience wrapper for treating warnings as errors.
     Warnings(Vec<MergeWarning>),
 }
@@ -37,8 +141,23 @@ impl std::error::Error for ConfpilerError {
             ConfpilerError::ConfigError(_) => None,
             ConfpilerError::DuplicateConfig(_) => None,
             ConfpilerError::DuplicateKey(_) => None,
+            ConfpilerError::InvalidKey { .. } => None,
+            ConfpilerError::ReferenceCycle(_) => None,
+            ConfpilerError::UnresolvedReference(_) => None,
+            ConfpilerError::MissingEnvVar { .. } => None,
+            ConfpilerError::UnresolvedPlaceholders(_) => None,
+            ConfpilerError::InvalidDotenv { .. } => None,
+            ConfpilerError::AmbiguousConfig { .. } => None,
+            ConfpilerError::DirectoryNotFound(_) => None,
+            ConfpilerError::MissingDefault { .. } => None,
+            ConfpilerError::EnvironmentCycle(_) => None,
+            ConfpilerError::UnknownEnvironment { .. } => None,
             ConfpilerError::NoConfigSpecified => None,
             ConfpilerError::UnsupportedArray(_) => None,
+            ConfpilerError::SerializeError(_) => None,
+            ConfpilerError::UnflattenConflict(_) => None,
+            ConfpilerError::InvalidSchema(_) => None,
+            ConfpilerError::SchemaViolations(_) => None,
             ConfpilerError::Warnings(_) => None,
         }
     }
@@ -54,6 +173,104 @@ impl std::fmt::Display for ConfpilerError {
             ConfpilerError::DuplicateKey(ref key) => {
                 write!(f, "the key \"{key}\" would be overwritten by another value in the same configuration file")
             }
+            ConfpilerError::InvalidKey {
+                ref key,
+                ref source_key,
+                ref origin,
+            } => {
+                write!(f, "the key \"{key}\" (from \"{source_key}\" in \"{origin}\") is not a valid environment variable name")
+            }
+            ConfpilerError::ReferenceCycle(ref chain) => {
+                write!(f, "the references {} form a cycle", chain.join(" -> "))
+            }
+            ConfpilerError::UnresolvedReference(ref chain) => {
+                // the chain always contains at least the missing key
+                let missing = chain.last().map(|k| k.as_str()).unwrap_or_default();
+                write!(
+                    f,
+                    "the key \"{missing}\" does not exist (referenced by {})",
+                    chain.join(" -> ")
+                )
+            }
+            ConfpilerError::MissingEnvVar {
+                ref var,
+                ref key,
+                ref message,
+            } => {
+                write!(
+                    f,
+                    "the environment variable \"{var}\" referenced by \"{key}\" is not set"
+                )?;
+
+                if let Some(message) = message {
+                    write!(f, ": {message}")?;
+                }
+
+                Ok(())
+            }
+            ConfpilerError::UnresolvedPlaceholders(ref keys) => {
+                write!(
+                    f,
+                    "the following keys must be set by another config, but still have the required placeholder value: {}",
+                    keys.join(", ")
+                )
+            }
+            ConfpilerError::InvalidDotenv {
+                ref origin,
+                ref line,
+                ref reason,
+            } => {
+                write!(
+                    f,
+                    "unable to parse \"{origin}\" as a dotenv file: {reason} (line {line})"
+                )
+            }
+            ConfpilerError::AmbiguousConfig {
+                ref path,
+                ref candidates,
+            } => {
+                write!(
+                    f,
+                    "the config \"{path}\" is ambiguous, it matches: {}",
+                    candidates.join(", ")
+                )
+            }
+            ConfpilerError::DirectoryNotFound(ref directory) => {
+                write!(f, "\"{directory}\" is not a directory")
+            }
+            ConfpilerError::MissingDefault {
+                ref directory,
+                ref default,
+            } => {
+                write!(
+                    f,
+                    "the directory \"{directory}\" does not contain a default config (\"{default}\")"
+                )
+            }
+            ConfpilerError::EnvironmentCycle(ref chain) => {
+                write!(
+                    f,
+                    "the environments {} inherit from each other in a cycle",
+                    chain.join(" -> ")
+                )
+            }
+            ConfpilerError::UnknownEnvironment {
+                ref environment,
+                ref available,
+                ref suggestion,
+            } => {
+                write!(f, "no config found for the environment \"{environment}\"")?;
+
+                if let Some(suggestion) = suggestion {
+                    write!(f, " (did you mean \"{suggestion}\"?)")?;
+                }
+
+                if available.is_empty() {
+                    write!(f, ", and no environments are available")
+                } else {
+                    write!(f, ", available environments: {}", available.join(", "))
+                }
+            }
             ConfpilerError::NoConfigSpecified => {
                 write!(
                     f,
@@ -61,7 +278,23 @@ impl std::fmt::Display for ConfpilerError {
                 )
             }
             ConfpilerError::UnsupportedArray(ref key) => {
-                write!(f, "the array at \"{key}\" is unsupported (arrays must not contain arrays or maps to be condidered valid)")
+                write!(f, "the array at \"{key}\" is unsupported (arrays must not contain arrays or maps to be condidered valid, unless encoded as JSON)")
+            }
+            ConfpilerError::SerializeError(ref msg) => {
+                write!(f, "unable to render document: {msg}")
+            }
+            ConfpilerError::UnflattenConflict(ref key) => {
+                write!(f, "the key \"{key}\" conflicts with another key (a key cannot be both a value and the parent of other keys)")
+            }
+            ConfpilerError::InvalidSchema(ref msg) => {
+                write!(f, "invalid schema: {msg}")
+            }
+            ConfpilerError::SchemaViolations(ref violations) => {
+                let out = violations
+                    .iter()
+                    .map(|v| v.to_string())
+                    .collect::<Vec<String>>();
+                write!(f, "{}", out.join("\n"))
             }
             ConfpilerError::Warnings(ref warnings) => {
                 let mut out = warnings
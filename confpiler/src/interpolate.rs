@@ -0,0 +1,305 @@
+use indexmap::IndexMap;
+use std::collections::HashMap;
+
+use crate::error::{ConfpilerError, Result};
+
+/// The kinds of references to resolve when interpolating.
+#[derive(Debug, Clone, Copy, Default, Eq, PartialEq)]
+pub(crate) struct Interpolation {
+    /// Resolve `${KEY}` references to other keys.
+    pub(crate) keys: bool,
+    /// Resolve `${env:VAR}` references to the process environment.
+    pub(crate) env: bool,
+}
+
+impl Interpolation {
+    pub(crate) fn is_enabled(&self) -> bool {
+        self.keys || self.env
+    }
+}
+
+/// Resolve all `${KEY}` and/or `${env:VAR}` references in the given values.
+///
+/// Key references refer to other (flattened) keys in `items`, and may be
+/// nested, so long as they do not form a cycle. Environment references support
+/// `${env:VAR:-default}`, for a default value if `VAR` is unset or empty, and
+/// `${env:VAR:?message}`, for an error with the given message if `VAR` is unset
+/// or empty. References of a kind that is not enabled are left as-is. `$${` is
+/// an escaped, literal `${`.
+pub(crate) fn interpolate(
+    items: &mut IndexMap<String, String>,
+    interpolation: Interpolation,
+) -> Result<()> {
+    let mut resolver = Resolver {
+        raw: items,
+        interpolation,
+        resolved: HashMap::new(),
+        stack: Vec::new(),
+    };
+
+    let mut out = IndexMap::with_capacity(items.len());
+    for key in items.keys() {
+        out.insert(key.clone(), resolver.resolve(key)?);
+    }
+
+    *items = out;
+
+    Ok(())
+}
+
+struct Resolver<'a> {
+    raw: &'a IndexMap<String, String>,
+    interpolation: Interpolation,
+    resolved: HashMap<String, String>,
+    /// The chain of keys currently being resolved, for detecting cycles.
+    stack: Vec<String>,
+}
+
+impl Resolver<'_> {
+    fn resolve(&mut self, key: &str) -> Result<String> {
+        if let Some(value) = self.resolved.get(key) {
+            return Ok(value.clone());
+        }
+
+        if let Some(pos) = self.stack.iter().position(|k| k == key) {
+            let mut chain = self.stack[pos..].to_vec();
+            chain.push(key.to_string());
+            return Err(ConfpilerError::ReferenceCycle(chain));
+        }
+
+        let raw = match self.raw.get(key) {
+            Some(raw) => raw,
+            None => {
+                let mut chain = self.stack.clone();
+                chain.push(key.to_string());
+                return Err(ConfpilerError::UnresolvedReference(chain));
+            }
+        };
+
+        self.stack.push(key.to_string());
+        let value = self.expand(raw)?;
+        self.stack.pop();
+
+        self.resolved.insert(key.to_string(), value.clone());
+
+        Ok(value)
+    }
+
+    fn expand(&mut self, raw: &str) -> Result<String> {
+        // the overwhelmingly common case
+        if !raw.contains("${") {
+            return Ok(raw.to_string());
+        }
+
+        let mut out = String::with_capacity(raw.len());
+        let mut rest = raw;
+
+        while let Some(start) = rest.find("${") {
+            // an escaped reference is output literally, minus the escape
+            if rest[..start].ends_with('$') {
+                out.push_str(&rest[..start - 1]);
+                out.push_str("${");
+                rest = &rest[start + 2..];
+                continue;
+            }
+
+            out.push_str(&rest[..start]);
+
+            // an unterminated reference isn't a reference at all
+            let end = match rest[start..].find('}') {
+                Some(end) => start + end,
+                None => {
+                    out.push_str(&rest[start..]);
+                    return Ok(out);
+                }
+            };
+
+            let reference = &rest[start + 2..end];
+            match reference.strip_prefix("env:") {
+                Some(var) if self.interpolation.env => out.push_str(&self.resolve_env(var)?),
+                None if self.interpolation.keys => out.push_str(&self.resolve(reference)?),
+                _ => out.push_str(&rest[start..=end]),
+            }
+            rest = &rest[end + 1..];
+        }
+
+        out.push_str(rest);
+
+        Ok(out)
+    }
+
+    fn resolve_env(&self, reference: &str) -> Result<String> {
+        let split = reference
+            .find(':')
+            .filter(|pos| matches!(reference[pos + 1..].chars().next(), Some('-' | '?')));
+
+        let (var, fallback) = match split {
+            Some(pos) => (&reference[..pos], Some(&reference[pos..])),
+            None => (reference, None),
+        };
+
+        // like the shell, unset and empty variables are treated the same when
+        // a default or error message is specified
+        match (std::env::var(var).ok(), fallback) {
+            (Some(value), None) => Ok(value),
+            (Some(value), Some(_)) if !value.is_empty() => Ok(value),
+            (_, Some(fallback)) if fallback.starts_with(":-") => Ok(fallback[2..].to_string()),
+            (_, fallback) => Err(ConfpilerError::MissingEnvVar {
+                var: var.to_string(),
+                // we're always resolving some key when we get here
+                key: self.stack.last().cloned().unwrap_or_default(),
+                message: fallback.map(|f| f[2..].to_string()),
+            }),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    mod interpolate {
+        use super::super::*;
+
+        const KEYS: Interpolation = Interpolation {
+            keys: true,
+            env: false,
+        };
+
+        const ENV: Interpolation = Interpolation {
+            keys: false,
+            env: true,
+        };
+
+        fn items(pairs: &[(&str, &str)]) -> IndexMap<String, String> {
+            pairs
+                .iter()
+                .map(|(k, v)| (k.to_string(), v.to_string()))
+                .collect()
+        }
+
+        #[test]
+        fn resolves_references() {
+            let mut input = items(&[
+                ("URL", "postgres://${HOST}:${PORT}/app"),
+                ("HOST", "${DOMAIN}"),
+                ("PORT", "5432"),
+                ("DOMAIN", "db.example.com"),
+            ]);
+
+            let expected = items(&[
+                ("URL", "postgres://db.example.com:5432/app"),
+                ("HOST", "db.example.com"),
+                ("PORT", "5432"),
+                ("DOMAIN", "db.example.com"),
+            ]);
+
+            interpolate(&mut input, KEYS).expect("failed to interpolate");
+
+            assert_eq!(input, expected);
+        }
+
+        #[test]
+        fn handles_escapes_and_unterminated_references() {
+            let mut input = items(&[("FOO", "$${FOO} and $$ and ${BAR"), ("BAR", "$5")]);
+
+            let expected = items(&[("FOO", "${FOO} and $$ and ${BAR"), ("BAR", "$5")]);
+
+            interpolate(&mut input, KEYS).expect("failed to interpolate");
+
+            assert_eq!(input, expected);
+        }
+
+        #[test]
+        fn detects_cycles() {
+            let mut input = items(&[("FOO", "${BAR}"), ("BAR", "x${BAZ}"), ("BAZ", "${BAR}")]);
+
+            match interpolate(&mut input, KEYS) {
+                Err(ConfpilerError::ReferenceCycle(chain)) => {
+                    assert_eq!(chain, vec!["BAR", "BAZ", "BAR"]);
+                }
+                _ => panic!("expected a cycle"),
+            }
+
+            let mut input = items(&[("FOO", "${FOO}")]);
+
+            assert!(matches!(
+                interpolate(&mut input, KEYS),
+                Err(ConfpilerError::ReferenceCycle(_))
+            ));
+        }
+
+        #[test]
+        fn detects_missing_references() {
+            let mut input = items(&[("FOO", "${BAR}"), ("BAR", "${BAZ}")]);
+
+            match interpolate(&mut input, KEYS) {
+                Err(ConfpilerError::UnresolvedReference(chain)) => {
+                    assert_eq!(chain, vec!["FOO", "BAR", "BAZ"]);
+                }
+                _ => panic!("expected a missing reference"),
+            }
+        }
+
+        #[test]
+        fn resolves_environment_variables() {
+            std::env::set_var("CONFPILER_INTERPOLATE_SET", "abc123");
+            std::env::set_var("CONFPILER_INTERPOLATE_EMPTY", "");
+            std::env::remove_var("CONFPILER_INTERPOLATE_UNSET");
+
+            let mut input = items(&[
+                ("SHA", "${env:CONFPILER_INTERPOLATE_SET}"),
+                ("EMPTY", "${env:CONFPILER_INTERPOLATE_EMPTY}"),
+                ("REGION", "${env:CONFPILER_INTERPOLATE_UNSET:-us-east-1}"),
+                ("OTHER", "${env:CONFPILER_INTERPOLATE_EMPTY:-fallback}"),
+                ("TOKEN", "${env:CONFPILER_INTERPOLATE_SET:?must be set}"),
+                ("KEY", "${SHA}"),
+            ]);
+
+            let expected = items(&[
+                ("SHA", "abc123"),
+                ("EMPTY", ""),
+                ("REGION", "us-east-1"),
+                ("OTHER", "fallback"),
+                ("TOKEN", "abc123"),
+                // key references are not enabled
+                ("KEY", "${SHA}"),
+            ]);
+
+            interpolate(&mut input, ENV).expect("failed to interpolate");
+
+            assert_eq!(input, expected);
+        }
+
+        #[test]
+        fn leaves_environment_references_when_disabled() {
+            let mut input = items(&[("FOO", "${env:HOME}")]);
+            let expected = input.clone();
+
+            interpolate(&mut input, KEYS).expect("failed to interpolate");
+
+            assert_eq!(input, expected);
+        }
+
+        #[test]
+        fn detects_missing_environment_variables() {
+            std::env::remove_var("CONFPILER_INTERPOLATE_MISSING");
+
+            let mut input = items(&[("FOO", "${env:CONFPILER_INTERPOLATE_MISSING:?must be set}")]);
+
+            match interpolate(&mut input, ENV) {
+                Err(ConfpilerError::MissingEnvVar { var, key, message }) => {
+                    assert_eq!(var, "CONFPILER_INTERPOLATE_MISSING");
+                    assert_eq!(key, "FOO");
+                    assert_eq!(message, Some("must be set".to_string()));
+                }
+                _ => panic!("expected a missing env var"),
+            }
+
+            let mut input = items(&[("FOO", "${env:CONFPILER_INTERPOLATE_MISSING}")]);
+
+            assert!(matches!(
+                interpolate(&mut input, ENV),
+                Err(ConfpilerError::MissingEnvVar { message: None, .. })
+            ));
+        }
+    }
+}
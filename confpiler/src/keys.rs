@@ -0,0 +1,335 @@
+use std::sync::Arc;
+
+/// Determines how the individual components of a key are formatted before
+/// being joined into a flattened key.
+///
+/// # Examples
+/// ```
+/// use confpiler::KeyFormat;
+///
+/// assert_eq!(KeyFormat::Upper.format("maxConnections"), "MAXCONNECTIONS");
+/// assert_eq!(KeyFormat::ScreamingSnake.format("maxConnections"), "MAX_CONNECTIONS");
+/// assert_eq!(KeyFormat::Lower.format("maxConnections"), "maxconnections");
+///
+/// // custom formats can be any function or closure, including ones that
+/// // capture their own configuration
+/// let replacement = "_".to_string();
+/// let format = KeyFormat::custom(move |component| component.replace('-', &replacement));
+/// assert_eq!(format.format("max-connections"), "max_connections");
+/// ```
+#[derive(Clone, Default)]
+pub enum KeyFormat {
+    /// Components are converted to ascii uppercase (`foo_bar` becomes
+    /// `FOO_BAR`).
+    #[default]
+    Upper,
+
+    /// Components are left exactly as they appear in the config.
+    Preserve,
+
+    /// Components are converted to ascii lowercase (`Foo_Bar` becomes
+    /// `foo_bar`).
+    Lower,
+
+    /// Components are converted to ascii lowercase and are **always** joined
+    /// with `.`, regardless of the configured separator, producing keys like
+    /// `foo.bar`.
+    Dotted,
+
+    /// camelCase, PascalCase, kebab-case, etc. components are converted to
+    /// SCREAMING_SNAKE_CASE (`maxConnections` becomes `MAX_CONNECTIONS`).
+    ScreamingSnake,
+
+    /// Components are formatted with the given function.
+    ///
+    /// Functions can't be compared, so a custom format is never equal to any
+    /// other format, including itself. This also means that builders using a
+    /// custom format never compare equal.
+    Custom(Arc<dyn Fn(&str) -> String + Send + Sync>),
+}
+
+impl KeyFormat {
+    /// The string representations of the built-in variants, as accepted by
+    /// [from_str](std::str::FromStr::from_str).
+    pub const VARIANTS: &'static [&'static str] =
+        &["upper", "preserve", "lower", "dotted", "screaming-snake"];
+
+    /// Create a [Custom](KeyFormat::Custom) format from the given function.
+    pub fn custom<F>(f: F) -> Self
+    where
+        F: Fn(&str) -> String + Send + Sync + 'static,
+    {
+        Self::Custom(Arc::new(f))
+    }
+
+    /// Format a single component of a key.
+    pub fn format(&self, component: &str) -> String {
+        match self {
+            Self::Upper => component.to_ascii_uppercase(),
+            Self::Preserve => component.to_string(),
+            Self::Lower | Self::Dotted => component.to_ascii_lowercase(),
+            Self::ScreamingSnake => to_screaming_snake(component),
+            Self::Custom(f) => f(component),
+        }
+    }
+
+    /// The separator required by this format, if any.
+    pub fn separator(&self) -> Option<&'static str> {
+        match self {
+            Self::Dotted => Some("."),
+            _ => None,
+        }
+    }
+}
+
+impl std::fmt::Debug for KeyFormat {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Upper => write!(f, "Upper"),
+            Self::Preserve => write!(f, "Preserve"),
+            Self::Lower => write!(f, "Lower"),
+            Self::Dotted => write!(f, "Dotted"),
+            Self::ScreamingSnake => write!(f, "ScreamingSnake"),
+            Self::Custom(_) => write!(f, "Custom(..)"),
+        }
+    }
+}
+
+// there's no meaningful way to compare functions, so custom formats are never
+// equal
+impl PartialEq for KeyFormat {
+    fn eq(&self, other: &Self) -> bool {
+        match (self, other) {
+            (Self::Custom(_), _) | (_, Self::Custom(_)) => false,
+            _ => std::mem::discriminant(self) == std::mem::discriminant(other),
+        }
+    }
+}
+
+impl Eq for KeyFormat {}
+
+impl std::str::FromStr for KeyFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s {
+            "upper" => Ok(Self::Upper),
+            "preserve" => Ok(Self::Preserve),
+            "lower" => Ok(Self::Lower),
+            "dotted" => Ok(Self::Dotted),
+            "screaming-snake" => Ok(Self::ScreamingSnake),
+            _ => Err(format!(
+                "unknown key format \"{s}\" (expected one of: {})",
+                Self::VARIANTS.join(", ")
+            )),
+        }
+    }
+}
+
+/// Determines how [FlatConfigBuilder::build](crate::FlatConfigBuilder::build)
+/// treats generated keys that are not valid environment variable names.
+///
+/// A valid name consists solely of ascii letters, digits, and `_`, and does
+/// not start with a digit (the POSIX "portable" naming rules). Names that
+/// violate these rules, like `MY-APP__API.URL` or `1FOO`, are often rejected by
+/// shells and container runtimes.
+#[derive(Debug, Clone, Copy, Default, Eq, PartialEq)]
+pub enum InvalidKeyPolicy {
+    /// Invalid keys are emitted as-is.
+    #[default]
+    Allow,
+
+    /// Illegal characters in invalid keys are replaced with `_`, and keys
+    /// starting with a digit are prefixed with `_`, so `MY-APP__API.URL`
+    /// becomes `MY_APP__API_URL` and `1FOO` becomes `_1FOO`.
+    Sanitize,
+
+    /// Invalid keys result in an error.
+    Error,
+}
+
+impl InvalidKeyPolicy {
+    /// The string representations of the variants, as accepted by
+    /// [from_str](std::str::FromStr::from_str).
+    pub const VARIANTS: &'static [&'static str] = &["allow", "sanitize", "error"];
+}
+
+impl std::str::FromStr for InvalidKeyPolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s {
+            "allow" => Ok(Self::Allow),
+            "sanitize" => Ok(Self::Sanitize),
+            "error" => Ok(Self::Error),
+            _ => Err(format!(
+                "unknown policy \"{s}\" (expected one of: {})",
+                Self::VARIANTS.join(", ")
+            )),
+        }
+    }
+}
+
+/// Check if the given key is a portable environment variable name.
+pub(crate) fn is_valid_env_name(key: &str) -> bool {
+    let mut chars = key.chars();
+
+    match chars.next() {
+        Some(c) if c.is_ascii_alphabetic() || c == '_' => {
+            chars.all(|c| c.is_ascii_alphanumeric() || c == '_')
+        }
+        _ => false,
+    }
+}
+
+/// Convert the given key into a portable environment variable name.
+pub(crate) fn sanitize_env_name(key: &str) -> String {
+    let mut out = String::with_capacity(key.len() + 1);
+
+    if key.is_empty() || key.starts_with(|c: char| c.is_ascii_digit()) {
+        out.push('_');
+    }
+
+    out.extend(key.chars().map(|c| {
+        if c.is_ascii_alphanumeric() || c == '_' {
+            c
+        } else {
+            '_'
+        }
+    }));
+
+    out
+}
+
+fn to_screaming_snake(component: &str) -> String {
+    let chars = component.chars().collect::<Vec<_>>();
+    let mut out = String::with_capacity(component.len() + 4);
+
+    for (idx, c) in chars.iter().enumerate() {
+        if matches!(c, '-' | ' ' | '.') {
+            out.push('_');
+            continue;
+        }
+
+        if c.is_uppercase() && idx > 0 {
+            let prev = chars[idx - 1];
+            let next_is_lower = chars.get(idx + 1).map_or(false, |n| n.is_lowercase());
+
+            // we want a boundary for fooBar and foo2Bar, as well as before the
+            // last capital of an acronym, like HTTPServer
+            if prev.is_lowercase()
+                || prev.is_ascii_digit()
+                || (prev.is_uppercase() && next_is_lower)
+            {
+                out.push('_');
+            }
+        }
+
+        out.push(c.to_ascii_uppercase());
+    }
+
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    mod key_format {
+        use super::super::*;
+
+        #[test]
+        fn formats_components() {
+            assert_eq!(KeyFormat::Upper.format("foo_Bar"), "FOO_BAR");
+            assert_eq!(KeyFormat::Preserve.format("foo_Bar"), "foo_Bar");
+            assert_eq!(KeyFormat::Lower.format("foo_Bar"), "foo_bar");
+            assert_eq!(KeyFormat::Dotted.format("foo_Bar"), "foo_bar");
+        }
+
+        #[test]
+        fn converts_to_screaming_snake() {
+            let cases = [
+                ("maxConnections", "MAX_CONNECTIONS"),
+                ("MaxConnections", "MAX_CONNECTIONS"),
+                ("max-connections", "MAX_CONNECTIONS"),
+                ("max_connections", "MAX_CONNECTIONS"),
+                ("HTTPServer", "HTTP_SERVER"),
+                ("useHTTP", "USE_HTTP"),
+                ("foo2Bar", "FOO2_BAR"),
+                ("FOO", "FOO"),
+            ];
+
+            for (input, expected) in cases {
+                assert_eq!(KeyFormat::ScreamingSnake.format(input), expected);
+            }
+        }
+
+        #[test]
+        fn custom_formats() {
+            let prefix = "x_".to_string();
+            let format = KeyFormat::custom(move |c| format!("{prefix}{c}"));
+
+            assert_eq!(format.format("foo"), "x_foo");
+
+            // functions can't be compared, so custom formats are never equal
+            assert_ne!(format, format.clone());
+            assert_ne!(format, KeyFormat::Upper);
+            assert_eq!(KeyFormat::Upper, KeyFormat::Upper);
+        }
+
+        #[test]
+        fn only_dotted_requires_a_separator() {
+            assert_eq!(KeyFormat::Dotted.separator(), Some("."));
+            assert_eq!(KeyFormat::Upper.separator(), None);
+            assert_eq!(KeyFormat::ScreamingSnake.separator(), None);
+        }
+
+        #[test]
+        fn parsing() {
+            assert_eq!("upper".parse::<KeyFormat>(), Ok(KeyFormat::Upper));
+            assert_eq!(
+                "screaming-snake".parse::<KeyFormat>(),
+                Ok(KeyFormat::ScreamingSnake)
+            );
+            assert!("bogus".parse::<KeyFormat>().is_err());
+        }
+    }
+
+    mod env_names {
+        use super::super::*;
+
+        #[test]
+        fn validation() {
+            assert!(is_valid_env_name("FOO"));
+            assert!(is_valid_env_name("FOO__BAR_1"));
+            assert!(is_valid_env_name("_FOO"));
+            assert!(is_valid_env_name("foo"));
+
+            assert!(!is_valid_env_name(""));
+            assert!(!is_valid_env_name("1FOO"));
+            assert!(!is_valid_env_name("MY-APP"));
+            assert!(!is_valid_env_name("API.URL"));
+            assert!(!is_valid_env_name("FOO BAR"));
+            assert!(!is_valid_env_name("FÖO"));
+        }
+
+        #[test]
+        fn sanitization() {
+            assert_eq!(sanitize_env_name("FOO__BAR"), "FOO__BAR");
+            assert_eq!(sanitize_env_name("MY-APP__API.URL"), "MY_APP__API_URL");
+            assert_eq!(sanitize_env_name("1FOO"), "_1FOO");
+            assert_eq!(sanitize_env_name("FÖO"), "F_O");
+            assert_eq!(sanitize_env_name(""), "_");
+
+            // sanitizing is idempotent
+            assert_eq!(sanitize_env_name("_1FOO"), "_1FOO");
+        }
+
+        #[test]
+        fn parsing_policy() {
+            assert_eq!(
+                "sanitize".parse::<InvalidKeyPolicy>(),
+                Ok(InvalidKeyPolicy::Sanitize)
+            );
+            assert!("bogus".parse::<InvalidKeyPolicy>().is_err());
+        }
+    }
+}
@@ -1,7 +1,32 @@
 #![doc = include_str!("../README.md")]
 mod config;
+mod diff;
+mod dotenv;
 pub mod error;
+mod interpolate;
+mod keys;
+mod provenance;
+mod schema;
+mod source;
+mod unflatten;
 
+/// The version of `indexmap` used by [FlatConfig::items].
+pub use indexmap;
+
+pub use crate::config::ArrayStrategy;
 pub use crate::config::FlatConfig;
 pub use crate::config::FlatConfigBuilder;
 pub use crate::config::MergeWarning;
+pub use crate::config::NewKeyPolicy;
+pub use crate::config::TypeChangePolicy;
+pub use crate::diff::KeyDiff;
+pub use crate::keys::InvalidKeyPolicy;
+pub use crate::keys::KeyFormat;
+pub use crate::provenance::Assignment;
+pub use crate::provenance::Provenance;
+pub use crate::provenance::ValueType;
+pub use crate::schema::Schema;
+pub use crate::schema::SchemaViolation;
+pub use crate::source::SourceFormat;
+pub use crate::unflatten::DocumentFormat;
+pub use crate::unflatten::Unflattener;
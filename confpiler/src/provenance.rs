@@ -0,0 +1,192 @@
+use std::fmt;
+
+/// The type of a value before it was flattened into a [String].
+///
+/// Integers of any size are considered the same type.
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Hash)]
+pub enum ValueType {
+    Boolean,
+    Integer,
+    Float,
+    String,
+    Array,
+    Table,
+    /// An explicit null, which only appears in provenance when nulls delete
+    /// keys.
+    Null,
+}
+
+impl ValueType {
+    /// Check if replacing a value of this type with a value of the `other`
+    /// type is an ordinary override, which is the case if they are the same
+    /// type or both numbers (so `1` can be overridden by `1.5`).
+    pub(crate) fn is_compatible_with(&self, other: Self) -> bool {
+        *self == other
+            || matches!(
+                (self, other),
+                (Self::Integer, Self::Float) | (Self::Float, Self::Integer)
+            )
+    }
+
+    /// Infer the type of an untyped value, but only if a value of that type
+    /// would be flattened into the exact same string (so `"007"` and `"1.0"`
+    /// are strings).
+    pub(crate) fn infer(value: &str) -> Self {
+        if value == "true" || value == "false" {
+            Self::Boolean
+        } else if value
+            .parse::<i64>()
+            .map_or(false, |i| i.to_string() == value)
+        {
+            Self::Integer
+        } else if value.contains('.')
+            && value
+                .parse::<f64>()
+                .map_or(false, |f| f.is_finite() && f.to_string() == value)
+        {
+            Self::Float
+        } else {
+            Self::String
+        }
+    }
+}
+
+impl fmt::Display for ValueType {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            Self::Boolean => "boolean",
+            Self::Integer => "integer",
+            Self::Float => "float",
+            Self::String => "string",
+            Self::Array => "array",
+            Self::Table => "table",
+            Self::Null => "null",
+        };
+
+        write!(f, "{name}")
+    }
+}
+
+/// A single instance of a config assigning a value to a flattened key.
+///
+/// When nulls delete keys, deleting a key is recorded as an assignment with an
+/// empty value and a [ValueType::Null] type.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct Assignment {
+    origin: String,
+    value: String,
+    value_type: ValueType,
+}
+
+impl Assignment {
+    pub(crate) fn new(origin: &str, value: &str, value_type: ValueType) -> Self {
+        Self {
+            origin: origin.to_string(),
+            value: value.to_string(),
+            value_type,
+        }
+    }
+
+    /// The config that made this assignment.
+    pub fn origin(&self) -> &str {
+        &self.origin
+    }
+
+    /// The value that was assigned.
+    pub fn value(&self) -> &str {
+        &self.value
+    }
+
+    /// The type of the value that was assigned, prior to flattening.
+    pub fn value_type(&self) -> ValueType {
+        self.value_type
+    }
+
+    /// Whether this assignment deleted the key.
+    pub fn is_deletion(&self) -> bool {
+        self.value_type == ValueType::Null
+    }
+}
+
+/// The history of a flattened key in a [FlatConfig](crate::FlatConfig).
+///
+/// This records every config that assigned a value to a given key, in the
+/// order those configs were merged. The last assignment is the one that
+/// determined the final value of the key.
+///
+/// # Examples
+/// ```no_run
+/// use confpiler::FlatConfig;
+/// # use confpiler::error::ConfpilerError;
+/// # fn main() -> Result<(), ConfpilerError> {
+/// let (conf, _) = FlatConfig::builder()
+///     .add_config("foo/default")
+///     .add_config("foo/production")
+///     .build()?;
+///
+/// if let Some(provenance) = conf.provenance("DB__HOST") {
+///     println!("DB__HOST was set by {}", provenance.origin());
+///
+///     for assignment in provenance.assignments() {
+///         println!("    {} -> {}", assignment.origin(), assignment.value());
+///     }
+/// }
+/// # Ok(())
+/// # }
+/// ```
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct Provenance {
+    assignments: Vec<Assignment>,
+}
+
+impl Provenance {
+    pub(crate) fn new(origin: &str, value: &str, value_type: ValueType) -> Self {
+        Self {
+            assignments: vec![Assignment::new(origin, value, value_type)],
+        }
+    }
+
+    pub(crate) fn extend(&mut self, other: &Self) {
+        self.assignments.extend_from_slice(&other.assignments);
+    }
+
+    pub(crate) fn delete(&mut self, origin: &str) {
+        self.assignments
+            .push(Assignment::new(origin, "", ValueType::Null));
+    }
+
+    /// The config that set the final value of the key.
+    pub fn origin(&self) -> &str {
+        // provenance is never constructed without at least one assignment
+        self.assignments
+            .last()
+            .map(|a| a.origin())
+            .unwrap_or_default()
+    }
+
+    /// The type of the final value of the key, prior to flattening.
+    pub fn value_type(&self) -> ValueType {
+        self.assignments
+            .last()
+            .map(|a| a.value_type())
+            .unwrap_or(ValueType::String)
+    }
+
+    /// Whether the key was deleted by the last config to assign it a value.
+    pub fn is_deleted(&self) -> bool {
+        self.assignments
+            .last()
+            .map(|a| a.is_deletion())
+            .unwrap_or_default()
+    }
+
+    /// Every assignment made to the key, in merge order.
+    pub fn assignments(&self) -> &[Assignment] {
+        &self.assignments
+    }
+
+    /// The configs that assigned a value to the key, in merge order.
+    pub fn origins(&self) -> impl Iterator<Item = &str> {
+        self.assignments.iter().map(|a| a.origin())
+    }
+}
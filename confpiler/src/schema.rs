@@ -0,0 +1,566 @@
+use config::{Config, File, FileFormat, Value, ValueKind};
+use indexmap::IndexMap;
+use regex::Regex;
+use std::fmt;
+use url::Url;
+
+use crate::config::FlatConfig;
+use crate::error::{ConfpilerError, Result};
+use crate::source::SourceFormat;
+
+/// Expectations for the final, flattened keys of a [FlatConfig].
+///
+/// A schema is a map of keys (as they appear in the final config, including
+/// any prefix) to the expectations for their values:
+///
+/// ```yaml
+/// DB__HOST:
+///   required: true
+/// DB__PORT:
+///   type: int
+///   min: 1
+///   max: 65535
+/// DB__URL: url
+/// LOG_LEVEL:
+///   type: enum
+///   values: [debug, info, warn, error]
+/// REQUEST_TIMEOUT: duration
+/// REGION:
+///   type: regex
+///   pattern: "^[a-z]+-[a-z]+-[0-9]$"
+/// ```
+///
+/// * `type` is one of `string` (the default), `int`, `float`, `bool`, `url`,
+///   `duration` (like `30s`, `250ms` or `1h30m`), `enum` (which requires
+///   `values`), or `regex` (which requires a `pattern`). A type may also be
+///   given on its own, in place of the map.
+/// * `required` defaults to `false`. Keys that are not required are only
+///   checked if they are set.
+/// * `min` and `max` are inclusive, and only apply to `int` and `float`.
+/// * A `pattern` is not anchored, so `^` and `$` are needed to match the
+///   entire value.
+///
+/// # Examples
+/// ```
+/// use confpiler::{FlatConfig, Schema, SchemaViolation, SourceFormat};
+/// # use confpiler::error::ConfpilerError;
+/// # fn main() -> Result<(), ConfpilerError> {
+///
+/// let schema = Schema::from_str("PORT:\n  type: int\n  max: 65535\n", SourceFormat::Yaml)?;
+///
+/// let (conf, _) = FlatConfig::builder()
+///     .add_config_str("app", "PORT=99999", SourceFormat::Dotenv)
+///     .build()?;
+///
+/// assert_eq!(
+///     schema.validate(&conf),
+///     vec![SchemaViolation::Invalid {
+///         key: "PORT".to_string(),
+///         value: "99999".to_string(),
+///         origin: Some("app".to_string()),
+///         expected: "an integer of at most 65535".to_string(),
+///     }]
+/// );
+/// # Ok(())
+/// # }
+/// ```
+#[derive(Debug, Clone, Default, Eq, PartialEq)]
+pub struct Schema {
+    rules: IndexMap<String, Rule>,
+}
+
+impl Schema {
+    /// Load a schema from the given file, in any format supported by
+    /// `config-rs`.
+    pub fn from_file(path: &str) -> Result<Self> {
+        Self::from_config(
+            Config::builder()
+                .add_source(File::with_name(path))
+                .build()?,
+        )
+    }
+
+    /// Load a schema from the given string, in the given format.
+    ///
+    /// Schemas cannot be specified in the dotenv format.
+    pub fn from_str(content: &str, format: SourceFormat) -> Result<Self> {
+        let file_format = match format {
+            SourceFormat::Json => FileFormat::Json,
+            SourceFormat::Toml => FileFormat::Toml,
+            SourceFormat::Yaml => FileFormat::Yaml,
+            SourceFormat::Ini => FileFormat::Ini,
+            SourceFormat::Dotenv => {
+                return Err(ConfpilerError::InvalidSchema(
+                    "schemas cannot be specified in the dotenv format".to_string(),
+                ))
+            }
+        };
+
+        Self::from_config(
+            Config::builder()
+                .add_source(File::from_str(content, file_format))
+                .build()?,
+        )
+    }
+
+    fn from_config(conf: Config) -> Result<Self> {
+        let rules = conf
+            .cache
+            .into_table()?
+            .into_iter()
+            .map(|(key, value)| Rule::parse(&key, value).map(|rule| (key, rule)))
+            .collect::<Result<_>>()?;
+
+        Ok(Self { rules })
+    }
+
+    /// Check the given config against this schema, returning every
+    /// violation, in the order the keys appear in the schema.
+    pub fn validate(&self, config: &FlatConfig) -> Vec<SchemaViolation> {
+        let mut violations = Vec::new();
+
+        for (key, rule) in self.rules.iter() {
+            match config.items().get(key) {
+                None if rule.required => violations.push(SchemaViolation::Missing {
+                    key: key.to_string(),
+                }),
+                Some(value) if !rule.check(value) => violations.push(SchemaViolation::Invalid {
+                    key: key.to_string(),
+                    value: value.to_string(),
+                    origin: config.origin_of(key).map(|o| o.to_string()),
+                    expected: rule.expected(),
+                }),
+                _ => {}
+            }
+        }
+
+        violations
+    }
+}
+
+/// Indicates a [FlatConfig] does not match a [Schema].
+#[derive(Debug, Clone, Eq, PartialEq)]
+#[non_exhaustive]
+pub enum SchemaViolation {
+    /// A required key is not set.
+    Missing { key: String },
+
+    /// The value of a key does not match the expectations for that key.
+    ///
+    /// The origin is the config that set the final value of the key, if known.
+    Invalid {
+        key: String,
+        value: String,
+        origin: Option<String>,
+        expected: String,
+    },
+}
+
+impl fmt::Display for SchemaViolation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Missing { ref key } => {
+                write!(f, "'{key}' is required, but is not set")
+            }
+            Self::Invalid {
+                ref key,
+                ref value,
+                ref origin,
+                ref expected,
+            } => {
+                write!(f, "'{key}' is set to '{value}'")?;
+
+                if let Some(origin) = origin {
+                    write!(f, " by '{origin}'")?;
+                }
+
+                write!(f, ", but should be {expected}")
+            }
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+struct Rule {
+    required: bool,
+    kind: Kind,
+    min: Option<f64>,
+    max: Option<f64>,
+}
+
+// the bounds are never NaN
+impl Eq for Rule {}
+
+impl Rule {
+    fn parse(key: &str, value: Value) -> Result<Self> {
+        let invalid = |reason: String| ConfpilerError::InvalidSchema(format!("\"{key}\" {reason}"));
+
+        let mut kind = "string".to_string();
+        let mut required = false;
+        let mut min = None;
+        let mut max = None;
+        let mut values = None;
+        let mut pattern = None;
+
+        match value.kind {
+            ValueKind::String(name) => kind = name,
+            ValueKind::Table(table) => {
+                for (field, value) in table.into_iter() {
+                    let res = match field.as_str() {
+                        "type" => value.into_string().map(|v| kind = v),
+                        "required" => value.into_bool().map(|v| required = v),
+                        "min" => value.into_float().map(|v| min = Some(v)),
+                        "max" => value.into_float().map(|v| max = Some(v)),
+                        "values" => value.into_array().and_then(|array| {
+                            array
+                                .into_iter()
+                                .map(|v| v.into_string())
+                                .collect::<std::result::Result<Vec<_>, _>>()
+                                .map(|v| values = Some(v))
+                        }),
+                        "pattern" => value.into_string().map(|v| pattern = Some(v)),
+                        _ => return Err(invalid(format!("unknown field \"{field}\""))),
+                    };
+
+                    res.map_err(|e| invalid(format!("invalid \"{field}\" ({e})")))?;
+                }
+            }
+            _ => return Err(invalid("expected a type or a map".to_string())),
+        }
+
+        let kind = match kind.as_str() {
+            "string" => Kind::String,
+            "int" => Kind::Int,
+            "float" => Kind::Float,
+            "bool" => Kind::Bool,
+            "url" => Kind::Url,
+            "duration" => Kind::Duration,
+            "enum" => Kind::Enum(
+                values
+                    .take()
+                    .ok_or_else(|| invalid("an enum requires values".to_string()))?,
+            ),
+            "regex" => Kind::Regex(
+                pattern
+                    .take()
+                    .ok_or_else(|| invalid("a regex requires a pattern".to_string()))
+                    .and_then(|p| Regex::new(&p).map_err(|e| invalid(e.to_string())))?,
+            ),
+            _ => {
+                return Err(invalid(format!(
+                    "unknown type \"{kind}\" (expected one of: {})",
+                    Kind::VARIANTS.join(", ")
+                )))
+            }
+        };
+
+        if values.is_some() {
+            return Err(invalid("only an enum may specify values".to_string()));
+        }
+
+        if pattern.is_some() {
+            return Err(invalid("only a regex may specify a pattern".to_string()));
+        }
+
+        if min.is_some() || max.is_some() {
+            if !matches!(kind, Kind::Int | Kind::Float) {
+                return Err(invalid(
+                    "only an int or a float may specify a range".to_string(),
+                ));
+            }
+
+            if min.map_or(false, f64::is_nan) || max.map_or(false, f64::is_nan) {
+                return Err(invalid("a range cannot be NaN".to_string()));
+            }
+
+            if let (Some(min), Some(max)) = (min, max) {
+                if min > max {
+                    return Err(invalid(format!("the min ({min}) exceeds the max ({max})")));
+                }
+            }
+        }
+
+        Ok(Self {
+            required,
+            kind,
+            min,
+            max,
+        })
+    }
+
+    fn check(&self, value: &str) -> bool {
+        let number = match self.kind {
+            Kind::String => return true,
+            Kind::Int => value.parse::<i64>().ok().map(|i| i as f64),
+            Kind::Float => value.parse::<f64>().ok().filter(|f| f.is_finite()),
+            Kind::Bool => return value == "true" || value == "false",
+            Kind::Url => return Url::parse(value).map_or(false, |url| !url.cannot_be_a_base()),
+            Kind::Duration => return is_duration(value),
+            Kind::Enum(ref values) => return values.iter().any(|v| v == value),
+            Kind::Regex(ref re) => return re.is_match(value),
+        };
+
+        number.map_or(false, |n| {
+            self.min.map_or(true, |min| n >= min) && self.max.map_or(true, |max| n <= max)
+        })
+    }
+
+    fn expected(&self) -> String {
+        let description = self.kind.describe();
+
+        match (self.min, self.max) {
+            (Some(min), Some(max)) => format!("{description} between {min} and {max}"),
+            (Some(min), None) => format!("{description} of at least {min}"),
+            (None, Some(max)) => format!("{description} of at most {max}"),
+            (None, None) => description,
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+enum Kind {
+    String,
+    Int,
+    Float,
+    Bool,
+    Url,
+    Duration,
+    Enum(Vec<String>),
+    Regex(Regex),
+}
+
+impl Kind {
+    const VARIANTS: &'static [&'static str] = &[
+        "string", "int", "float", "bool", "url", "duration", "enum", "regex",
+    ];
+
+    fn describe(&self) -> String {
+        match self {
+            Self::String => "a string".to_string(),
+            Self::Int => "an integer".to_string(),
+            Self::Float => "a number".to_string(),
+            Self::Bool => "true or false".to_string(),
+            Self::Url => "a url".to_string(),
+            Self::Duration => "a duration (like 30s or 1h30m)".to_string(),
+            Self::Enum(ref values) => format!("one of: {}", values.join(", ")),
+            Self::Regex(ref re) => format!("a value matching '{re}'"),
+        }
+    }
+}
+
+// regexes aren't comparable, but their patterns are
+impl PartialEq for Kind {
+    fn eq(&self, other: &Self) -> bool {
+        match (self, other) {
+            (Self::Enum(a), Self::Enum(b)) => a == b,
+            (Self::Regex(a), Self::Regex(b)) => a.as_str() == b.as_str(),
+            _ => std::mem::discriminant(self) == std::mem::discriminant(other),
+        }
+    }
+}
+
+/// Check if the given value is a duration made of one or more whole numbers
+/// followed by a unit, like `30s`, `250ms`, or `1h30m`.
+fn is_duration(value: &str) -> bool {
+    let mut rest = value;
+
+    if rest.is_empty() {
+        return false;
+    }
+
+    while !rest.is_empty() {
+        let digits = rest
+            .find(|c: char| !c.is_ascii_digit())
+            .unwrap_or(rest.len());
+        if digits == 0 {
+            return false;
+        }
+        rest = &rest[digits..];
+
+        let unit = rest
+            .find(|c: char| c.is_ascii_digit())
+            .unwrap_or(rest.len());
+        if !matches!(&rest[..unit], "ns" | "us" | "ms" | "s" | "m" | "h" | "d") {
+            return false;
+        }
+        rest = &rest[unit..];
+    }
+
+    true
+}
+
+#[cfg(test)]
+mod tests {
+    mod schema {
+        use super::super::*;
+
+        fn config(content: &str) -> FlatConfig {
+            FlatConfig::builder()
+                .add_config_str("test.env", content, SourceFormat::Dotenv)
+                .build()
+                .expect("failed to build config")
+                .0
+        }
+
+        fn schema(content: &str) -> Schema {
+            Schema::from_str(content, SourceFormat::Yaml).expect("invalid schema")
+        }
+
+        fn invalid(key: &str, value: &str, expected: &str) -> SchemaViolation {
+            SchemaViolation::Invalid {
+                key: key.to_string(),
+                value: value.to_string(),
+                origin: Some("test.env".to_string()),
+                expected: expected.to_string(),
+            }
+        }
+
+        #[test]
+        fn validating_types() {
+            let schema = schema(
+                r#"
+NAME: string
+PORT: int
+RATIO: float
+DEBUG: bool
+URL: url
+TIMEOUT: duration
+LEVEL:
+  type: enum
+  values: [debug, info]
+REGION:
+  type: regex
+  pattern: "^[a-z]+-[0-9]$"
+"#,
+            );
+
+            let valid = config(
+                "NAME=x\nPORT=80\nRATIO=0.5\nDEBUG=false\nURL=https://foo.bar/baz\nTIMEOUT=1h30m\nLEVEL=info\nREGION=east-1\n",
+            );
+
+            assert!(schema.validate(&valid).is_empty());
+
+            let invalid_config = config(
+                "NAME=x\nPORT=80.5\nRATIO=abc\nDEBUG=yes\nURL=foo.bar\nTIMEOUT=30\nLEVEL=warn\nREGION=East-1\n",
+            );
+
+            assert_eq!(
+                schema.validate(&invalid_config),
+                vec![
+                    invalid("PORT", "80.5", "an integer"),
+                    invalid("RATIO", "abc", "a number"),
+                    invalid("DEBUG", "yes", "true or false"),
+                    invalid("URL", "foo.bar", "a url"),
+                    invalid("TIMEOUT", "30", "a duration (like 30s or 1h30m)"),
+                    invalid("LEVEL", "warn", "one of: debug, info"),
+                    invalid("REGION", "East-1", "a value matching '^[a-z]+-[0-9]$'"),
+                ]
+            );
+        }
+
+        #[test]
+        fn validating_ranges() {
+            let schema = schema(
+                "
+PORT:
+  type: int
+  min: 1
+  max: 65535
+WORKERS:
+  type: int
+  min: 1
+RATIO:
+  type: float
+  max: 1
+",
+            );
+
+            assert!(schema
+                .validate(&config("PORT=65535\nWORKERS=1\nRATIO=1.0\n"))
+                .is_empty());
+
+            assert_eq!(
+                schema.validate(&config("PORT=0\nWORKERS=0\nRATIO=1.5\n")),
+                vec![
+                    invalid("PORT", "0", "an integer between 1 and 65535"),
+                    invalid("WORKERS", "0", "an integer of at least 1"),
+                    invalid("RATIO", "1.5", "a number of at most 1"),
+                ]
+            );
+        }
+
+        #[test]
+        fn required_keys() {
+            let schema = schema("FOO:\n  required: true\nBAR:\n  type: int\n");
+
+            assert_eq!(
+                schema.validate(&config("BAZ=1\n")),
+                vec![SchemaViolation::Missing {
+                    key: "FOO".to_string()
+                }]
+            );
+
+            assert!(schema.validate(&config("FOO=\n")).is_empty());
+        }
+
+        #[test]
+        fn invalid_schemas() {
+            let cases = [
+                "FOO: bogus",
+                "FOO: [int]",
+                "FOO:\n  tpye: int\n",
+                "FOO:\n  required: maybe\n",
+                "FOO:\n  type: enum\n",
+                "FOO:\n  type: regex\n",
+                "FOO:\n  type: regex\n  pattern: '['\n",
+                "FOO:\n  type: string\n  values: [a]\n",
+                "FOO:\n  type: string\n  pattern: a\n",
+                "FOO:\n  type: string\n  min: 1\n",
+                "FOO:\n  type: int\n  min: 2\n  max: 1\n",
+            ];
+
+            for case in cases {
+                assert!(
+                    matches!(
+                        Schema::from_str(case, SourceFormat::Yaml),
+                        Err(ConfpilerError::InvalidSchema(_))
+                    ),
+                    "{case}"
+                );
+            }
+
+            assert!(Schema::from_str("FOO=int", SourceFormat::Dotenv).is_err());
+        }
+
+        #[test]
+        fn display() {
+            assert_eq!(
+                invalid("PORT", "0", "an integer").to_string(),
+                "'PORT' is set to '0' by 'test.env', but should be an integer"
+            );
+
+            assert_eq!(
+                SchemaViolation::Missing {
+                    key: "FOO".to_string()
+                }
+                .to_string(),
+                "'FOO' is required, but is not set"
+            );
+        }
+    }
+
+    mod durations {
+        use super::super::*;
+
+        #[test]
+        fn parsing() {
+            for valid in ["30s", "250ms", "1h30m", "2d", "10us", "5ns", "0s"] {
+                assert!(is_duration(valid), "{valid}");
+            }
+
+            for invalid in ["", "30", "s", "1.5s", "30 s", "-1s", "1y", "1h30"] {
+                assert!(!is_duration(invalid), "{invalid}");
+            }
+        }
+    }
+}
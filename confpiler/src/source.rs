@@ -0,0 +1,708 @@
+use config::{Config, ConfigError, File, FileFormat};
+use std::collections::{BTreeSet, HashMap};
+use std::path::Path;
+
+use crate::config::{flatten_into, FlattenOptions, Flattened};
+use crate::dotenv;
+use crate::error::{ConfpilerError, Result};
+
+/// The formats of configs supplied as strings via
+/// [FlatConfigBuilder::add_config_str](crate::FlatConfigBuilder::add_config_str).
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum SourceFormat {
+    Json,
+    Toml,
+    Yaml,
+    Ini,
+    /// Like dotenv files, keys are considered to already be flattened.
+    Dotenv,
+}
+
+impl SourceFormat {
+    /// The string representations of the variants, as accepted by
+    /// [from_str](std::str::FromStr::from_str).
+    pub const VARIANTS: &'static [&'static str] = &["json", "toml", "yaml", "ini", "dotenv"];
+}
+
+impl std::str::FromStr for SourceFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s {
+            "json" => Ok(Self::Json),
+            "toml" => Ok(Self::Toml),
+            "yaml" => Ok(Self::Yaml),
+            "ini" => Ok(Self::Ini),
+            "dotenv" => Ok(Self::Dotenv),
+            _ => Err(format!(
+                "unknown format \"{s}\" (expected one of: {})",
+                Self::VARIANTS.join(", ")
+            )),
+        }
+    }
+}
+
+/// The basename of the file declaring the parent of each environment in a
+/// directory.
+pub(crate) const ENVIRONMENTS_BASENAME: &str = "environments";
+
+/// The role of a resolved [Source] in a
+/// [FlatConfigBuilder](crate::FlatConfigBuilder).
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub(crate) enum Layer {
+    /// A config that was added on its own.
+    Config,
+
+    /// The default config of a directory.
+    Default,
+
+    /// An environment-specific config of a directory.
+    Environment,
+}
+
+/// A single layer of a [FlatConfigBuilder](crate::FlatConfigBuilder).
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub(crate) enum Source {
+    /// A config file, as understood by `config-rs` (or a dotenv file).
+    Path(String),
+
+    /// A directory containing a default config and any number of
+    /// environment-specific configs.
+    Directory(String),
+
+    /// The contents of a config in the given format.
+    Str {
+        origin: String,
+        content: String,
+        format: SourceFormat,
+    },
+
+    /// An already-parsed config.
+    Value {
+        origin: String,
+        value: serde_json::Map<String, serde_json::Value>,
+    },
+}
+
+impl Source {
+    /// The name used to refer to this source in warnings, errors, and
+    /// provenance.
+    pub(crate) fn origin(&self) -> &str {
+        match self {
+            Self::Path(path) | Self::Directory(path) => path,
+            Self::Str { origin, .. } | Self::Value { origin, .. } => origin,
+        }
+    }
+
+    /// Resolve this source into the sources that are actually loaded, along
+    /// with their roles.
+    ///
+    /// A directory is resolved into its default config, followed by the
+    /// config for each of the given environments that exists, in order. An
+    /// environment matches a file by its full name or by its stem (so
+    /// `production` matches `production.yaml`). Every other source is
+    /// resolved to itself.
+    ///
+    /// Environments with a parent declared in the directory's environments
+    /// file are preceded by their ancestors, starting with the furthest one.
+    /// Parents are always looked up by stem, so `production-eu.yaml`
+    /// inherits the same parents as `production-eu`.
+    /// Configs are only loaded once, so environments sharing ancestors (or
+    /// inheriting from the default) are fine.
+    pub(crate) fn resolve(
+        &self,
+        default: &str,
+        environments: &[String],
+    ) -> Result<Vec<(Source, Layer)>> {
+        let directory = match self {
+            Self::Directory(directory) => directory,
+            _ => return Ok(vec![(self.clone(), Layer::Config)]),
+        };
+
+        let dir = Path::new(directory);
+        if !dir.is_dir() {
+            return Err(ConfpilerError::DirectoryNotFound(directory.to_string()));
+        }
+
+        let layer = |name: &str| Source::Path(dir.join(name).to_string_lossy().into_owned());
+
+        if !has_config(dir, default)? {
+            return Err(ConfpilerError::MissingDefault {
+                directory: directory.to_string(),
+                default: default.to_string(),
+            });
+        }
+
+        let parents = if environments.is_empty() {
+            HashMap::new()
+        } else {
+            load_parents(dir)?
+        };
+
+        let mut out = vec![(layer(default), Layer::Default)];
+
+        for environment in environments.iter() {
+            // the environment itself keeps its full name, in case more than
+            // one format exists for the same stem
+            let mut names = chain(environment_stem(environment), &parents)?;
+            names.pop();
+            names.push(environment.to_string());
+
+            for name in names {
+                let source = (layer(&name), Layer::Environment);
+                if !out.contains(&source) && has_config(dir, &name)? {
+                    out.push(source);
+                }
+            }
+        }
+
+        Ok(out)
+    }
+
+    /// Load and flatten this source into `output`.
+    pub(crate) fn load(&self, output: &mut Flattened, options: &FlattenOptions) -> Result<()> {
+        let conf = match self {
+            Self::Directory(_) => unreachable!("directories are resolved before loading"),
+            // dotenv files are handled by us, since config-rs doesn't support
+            // them
+            Self::Path(path) => match dotenv::dotenv_path(path)? {
+                Some(path) => {
+                    let contents = std::fs::read_to_string(path)
+                        .map_err(|e| ConfigError::Foreign(Box::new(e)))?;
+
+                    return dotenv::parse_into(&contents, output, options);
+                }
+                None => Config::builder()
+                    .add_source(File::with_name(path))
+                    .build()?,
+            },
+            Self::Str {
+                content, format, ..
+            } => {
+                let file_format = match format {
+                    SourceFormat::Json => FileFormat::Json,
+                    SourceFormat::Toml => FileFormat::Toml,
+                    SourceFormat::Yaml => FileFormat::Yaml,
+                    SourceFormat::Ini => FileFormat::Ini,
+                    SourceFormat::Dotenv => return dotenv::parse_into(content, output, options),
+                };
+
+                Config::builder()
+                    .add_source(File::from_str(content, file_format))
+                    .build()?
+            }
+            Self::Value { value, .. } => {
+                let value: config::Value =
+                    serde_json::from_value(serde_json::Value::Object(value.clone()))
+                        .map_err(|e| ConfigError::Foreign(Box::new(e)))?;
+
+                return flatten_into(&value.into_table()?, output, options);
+            }
+        };
+
+        flatten_into(&conf.cache.into_table()?, output, options)
+    }
+}
+
+/// Check if the given directory contains a file with the given name or stem.
+fn has_config(dir: &Path, name: &str) -> Result<bool> {
+    if dir.join(name).is_file() {
+        return Ok(true);
+    }
+
+    for entry in dir
+        .read_dir()
+        .map_err(|e| ConfigError::Foreign(Box::new(e)))?
+    {
+        let path = entry.map_err(|e| ConfigError::Foreign(Box::new(e)))?.path();
+
+        if path.is_file() && path.file_stem().and_then(|s| s.to_str()) == Some(name) {
+            return Ok(true);
+        }
+    }
+
+    Ok(false)
+}
+
+/// Ensure every environment has a config in at least one of the directories
+/// in `sources`.
+///
+/// This does nothing if `sources` contains no directories.
+pub(crate) fn check_environments(
+    sources: &[Source],
+    default: &str,
+    environments: &[String],
+) -> Result<()> {
+    let dirs = sources
+        .iter()
+        .filter_map(|s| match s {
+            Source::Directory(directory) => Some(Path::new(directory)),
+            _ => None,
+        })
+        .collect::<Vec<_>>();
+
+    if dirs.is_empty() {
+        return Ok(());
+    }
+
+    'environments: for environment in environments.iter() {
+        for dir in dirs.iter() {
+            if has_config(dir, environment)? {
+                continue 'environments;
+            }
+        }
+
+        let available = available_environments(&dirs, default)?;
+        let suggestion = closest(environment, &available);
+
+        return Err(ConfpilerError::UnknownEnvironment {
+            environment: environment.to_string(),
+            available,
+            suggestion,
+        });
+    }
+
+    Ok(())
+}
+
+/// Get the sorted stems of every config in the given directories, other than
+/// the default and the environments file.
+///
+/// Only files with an extension of a supported format count as configs, and
+/// dotfiles are skipped, so files like `README.md` or `.gitkeep` aren't
+/// mistaken for environments.
+fn available_environments(dirs: &[&Path], default: &str) -> Result<Vec<String>> {
+    let mut available = BTreeSet::new();
+
+    for dir in dirs.iter() {
+        for entry in dir
+            .read_dir()
+            .map_err(|e| ConfigError::Foreign(Box::new(e)))?
+        {
+            let path = entry.map_err(|e| ConfigError::Foreign(Box::new(e)))?.path();
+
+            if !path.is_file() {
+                continue;
+            }
+
+            let name = path.file_name().and_then(|s| s.to_str());
+            let stem = path.file_stem().and_then(|s| s.to_str());
+            let is_config = path
+                .extension()
+                .and_then(|s| s.to_str())
+                .map_or(false, is_config_extension);
+
+            if let (Some(name), Some(stem), true) = (name, stem, is_config) {
+                if !name.starts_with('.')
+                    && name != default
+                    && stem != default
+                    && stem != ENVIRONMENTS_BASENAME
+                {
+                    available.insert(stem.to_string());
+                }
+            }
+        }
+    }
+
+    Ok(available.into_iter().collect())
+}
+
+/// Check if the given extension is that of a supported config format.
+fn is_config_extension(ext: &str) -> bool {
+    ext == "env" || dotenv::CONFIG_EXTENSIONS.contains(&ext)
+}
+
+/// Get the name of an environment without the extension of a supported config
+/// format, if it has one.
+fn environment_stem(environment: &str) -> &str {
+    match environment.rsplit_once('.') {
+        Some((stem, ext)) if is_config_extension(ext) => stem,
+        _ => environment,
+    }
+}
+
+/// Find the candidate closest to `name`, if any are close enough to plausibly
+/// be a typo of it.
+fn closest(name: &str, candidates: &[String]) -> Option<String> {
+    let threshold = (name.chars().count() / 3).max(1);
+
+    candidates
+        .iter()
+        .map(|c| (edit_distance(name, c), c))
+        .filter(|(distance, _)| *distance <= threshold)
+        .min_by_key(|(distance, _)| *distance)
+        .map(|(_, c)| c.to_string())
+}
+
+/// The Levenshtein distance between `a` and `b`.
+fn edit_distance(a: &str, b: &str) -> usize {
+    let b = b.chars().collect::<Vec<_>>();
+    let mut row = (0..=b.len()).collect::<Vec<_>>();
+
+    for (i, ca) in a.chars().enumerate() {
+        let mut diagonal = row[0];
+        row[0] = i + 1;
+
+        for (j, cb) in b.iter().enumerate() {
+            let substitution = diagonal + usize::from(ca != *cb);
+            diagonal = row[j + 1];
+            row[j + 1] = substitution.min(row[j] + 1).min(diagonal + 1);
+        }
+    }
+
+    row[b.len()]
+}
+
+/// Load the parent of every environment declared in the environments file in
+/// the given directory, if it has one.
+///
+/// The file maps environments to their metadata, like
+///
+/// ```yaml
+/// production-eu:
+///   parent: production
+/// ```
+fn load_parents(dir: &Path) -> Result<HashMap<String, String>> {
+    let mut parents = HashMap::new();
+
+    if !has_config(dir, ENVIRONMENTS_BASENAME)? {
+        return Ok(parents);
+    }
+
+    let path = dir.join(ENVIRONMENTS_BASENAME);
+    let conf = Config::builder()
+        .add_source(File::with_name(&path.to_string_lossy()))
+        .build()?;
+
+    for (environment, meta) in conf.cache.into_table()? {
+        if let Some(parent) = meta.into_table()?.remove("parent") {
+            parents.insert(environment, parent.into_string()?);
+        }
+    }
+
+    Ok(parents)
+}
+
+/// Get the given environment preceded by all of its ancestors, starting with
+/// the furthest one.
+fn chain(environment: &str, parents: &HashMap<String, String>) -> Result<Vec<String>> {
+    let mut chain = vec![environment.to_string()];
+
+    while let Some(parent) = chain.last().and_then(|e| parents.get(e)) {
+        if let Some(idx) = chain.iter().position(|e| e == parent) {
+            let mut cycle = chain.split_off(idx);
+            cycle.push(parent.to_string());
+            return Err(ConfpilerError::EnvironmentCycle(cycle));
+        }
+
+        chain.push(parent.to_string());
+    }
+
+    chain.reverse();
+    Ok(chain)
+}
+
+#[cfg(test)]
+mod tests {
+    mod source {
+        use super::super::*;
+        use indexmap::IndexMap;
+
+        fn load(source: Source) -> Result<IndexMap<String, String>> {
+            let mut out = Flattened::default();
+            source.load(&mut out, &FlattenOptions::default())?;
+            Ok(out.items)
+        }
+
+        fn expected(pairs: &[(&str, &str)]) -> IndexMap<String, String> {
+            pairs
+                .iter()
+                .map(|(k, v)| (k.to_string(), v.to_string()))
+                .collect()
+        }
+
+        #[test]
+        fn loading_strings() {
+            let cases = [
+                (SourceFormat::Json, r#"{"foo": {"bar": 10}, "baz": [1, 2]}"#),
+                (SourceFormat::Toml, "baz = [1, 2]\n[foo]\nbar = 10\n"),
+                (SourceFormat::Yaml, "foo:\n  bar: 10\nbaz: [1, 2]\n"),
+            ];
+
+            for (format, content) in cases {
+                let res = load(Source::Str {
+                    origin: "test".to_string(),
+                    content: content.to_string(),
+                    format,
+                })
+                .expect("failed to load");
+
+                let mut res = res.into_iter().collect::<Vec<_>>();
+                res.sort();
+
+                assert_eq!(
+                    res,
+                    vec![
+                        ("BAZ".to_string(), "1,2".to_string()),
+                        ("FOO__BAR".to_string(), "10".to_string()),
+                    ],
+                    "{format:?}"
+                );
+            }
+
+            let res = load(Source::Str {
+                origin: "test".to_string(),
+                content: "FOO=bar\n".to_string(),
+                format: SourceFormat::Dotenv,
+            })
+            .expect("failed to load");
+
+            assert_eq!(res, expected(&[("FOO", "bar")]));
+        }
+
+        #[test]
+        fn loading_values() {
+            let value = serde_json::json!({
+                "foo": {
+                    "bar": 10,
+                    "baz": 1.5,
+                },
+                "hoof": [true, "doof"],
+                "empty": null,
+            });
+
+            let res = load(Source::Value {
+                origin: "test".to_string(),
+                value: value.as_object().cloned().expect("not an object"),
+            })
+            .expect("failed to load");
+
+            assert_eq!(
+                res,
+                expected(&[
+                    ("FOO__BAR", "10"),
+                    ("FOO__BAZ", "1.5"),
+                    ("HOOF", "true,doof"),
+                ])
+            );
+        }
+
+        #[test]
+        fn invalid_strings() {
+            let res = load(Source::Str {
+                origin: "test".to_string(),
+                content: "foo: [".to_string(),
+                format: SourceFormat::Yaml,
+            });
+
+            assert!(res.is_err());
+        }
+
+        fn resolve(directory: &str, default: &str, environments: &[&str]) -> Result<Vec<String>> {
+            let environments = environments
+                .iter()
+                .map(|e| e.to_string())
+                .collect::<Vec<_>>();
+
+            Ok(Source::Directory(directory.to_string())
+                .resolve(default, &environments)?
+                .iter()
+                .map(|(s, _)| s.origin().to_string())
+                .collect())
+        }
+
+        #[test]
+        fn resolving_directories() {
+            let dir = "tests/fixtures/layered";
+
+            assert_eq!(
+                resolve(dir, "default", &[]).expect("failed to resolve"),
+                vec!["tests/fixtures/layered/default"]
+            );
+
+            // environments are matched by full name or stem, and missing
+            // environments are ignored
+            assert_eq!(
+                resolve(
+                    dir,
+                    "default.yaml",
+                    &["production", "bogus", "production-east.toml"]
+                )
+                .expect("failed to resolve"),
+                vec![
+                    "tests/fixtures/layered/default.yaml",
+                    "tests/fixtures/layered/production",
+                    "tests/fixtures/layered/production-east.toml",
+                ]
+            );
+        }
+
+        #[test]
+        fn resolving_layers() {
+            let layers = Source::Directory("tests/fixtures/layered".to_string())
+                .resolve("default", &["production".to_string()])
+                .expect("failed to resolve")
+                .into_iter()
+                .map(|(_, layer)| layer)
+                .collect::<Vec<_>>();
+
+            assert_eq!(layers, vec![Layer::Default, Layer::Environment]);
+        }
+
+        #[test]
+        fn resolving_invalid_directories() {
+            assert!(matches!(
+                resolve("tests/fixtures/layered", "base", &[]),
+                Err(ConfpilerError::MissingDefault { directory, default })
+                    if directory == "tests/fixtures/layered" && default == "base"
+            ));
+
+            assert!(matches!(
+                resolve("tests/fixtures/nope", "default", &[]),
+                Err(ConfpilerError::DirectoryNotFound(_))
+            ));
+
+            assert!(matches!(
+                resolve("tests/fixtures/file_one.yaml", "default", &[]),
+                Err(ConfpilerError::DirectoryNotFound(_))
+            ));
+        }
+
+        #[test]
+        fn resolving_inherited_environments() {
+            let dir = "tests/fixtures/inherited";
+
+            assert_eq!(
+                resolve(dir, "default", &["production-eu-canary"]).expect("failed to resolve"),
+                vec![
+                    "tests/fixtures/inherited/default",
+                    "tests/fixtures/inherited/production",
+                    "tests/fixtures/inherited/production-eu",
+                    "tests/fixtures/inherited/production-eu-canary",
+                ]
+            );
+
+            // shared ancestors are only loaded once
+            assert_eq!(
+                resolve(dir, "default", &["production-eu", "staging"]).expect("failed to resolve"),
+                vec![
+                    "tests/fixtures/inherited/default",
+                    "tests/fixtures/inherited/production",
+                    "tests/fixtures/inherited/production-eu",
+                    "tests/fixtures/inherited/staging",
+                ]
+            );
+        }
+
+        #[test]
+        fn detecting_environment_cycles() {
+            let parents = HashMap::from([
+                ("a".to_string(), "b".to_string()),
+                ("b".to_string(), "c".to_string()),
+                ("c".to_string(), "b".to_string()),
+                ("d".to_string(), "d".to_string()),
+            ]);
+
+            assert!(matches!(
+                chain("a", &parents),
+                Err(ConfpilerError::EnvironmentCycle(cycle)) if cycle == vec!["b", "c", "b"]
+            ));
+
+            assert!(matches!(
+                chain("d", &parents),
+                Err(ConfpilerError::EnvironmentCycle(cycle)) if cycle == vec!["d", "d"]
+            ));
+
+            assert_eq!(
+                chain("e", &parents).expect("failed to make chain"),
+                vec!["e".to_string()]
+            );
+        }
+
+        #[test]
+        fn checking_environments() {
+            let sources = vec![
+                Source::Path("tests/fixtures/file_one.yaml".to_string()),
+                Source::Directory("tests/fixtures/layered".to_string()),
+                Source::Directory("tests/fixtures/inherited".to_string()),
+            ];
+            let environments =
+                |envs: &[&str]| envs.iter().map(|e| e.to_string()).collect::<Vec<_>>();
+
+            check_environments(
+                &sources,
+                "default",
+                &environments(&["production-east", "production-eu.yaml"]),
+            )
+            .expect("environments should exist");
+
+            let res = check_environments(
+                &sources,
+                "default",
+                &environments(&["staging", "prodution-eu"]),
+            );
+
+            match res {
+                Err(ConfpilerError::UnknownEnvironment {
+                    environment,
+                    available,
+                    suggestion,
+                }) => {
+                    assert_eq!(environment, "prodution-eu");
+                    assert_eq!(
+                        available,
+                        vec![
+                            "production",
+                            "production-east",
+                            "production-eu",
+                            "production-eu-canary",
+                            "staging",
+                        ]
+                    );
+                    assert_eq!(suggestion, Some("production-eu".to_string()));
+                }
+                _ => panic!("unexpected result: {res:?}"),
+            }
+
+            // environments have no effect without directories
+            check_environments(&sources[..1], "default", &environments(&["bogus"]))
+                .expect("should be ignored without directories");
+        }
+
+        #[test]
+        fn suggesting_environments() {
+            let candidates = vec![
+                "development".to_string(),
+                "production".to_string(),
+                "staging".to_string(),
+            ];
+
+            assert_eq!(edit_distance("", "abc"), 3);
+            assert_eq!(edit_distance("kitten", "sitting"), 3);
+            assert_eq!(edit_distance("staging", "staging"), 0);
+
+            assert_eq!(
+                closest("prodution", &candidates),
+                Some("production".to_string())
+            );
+            assert_eq!(closest("stagign", &candidates), Some("staging".to_string()));
+            assert_eq!(closest("qa", &candidates), None);
+        }
+
+        #[test]
+        fn other_sources_resolve_to_themselves() {
+            let source = Source::Path("foo/bar".to_string());
+
+            assert_eq!(
+                source.resolve("default", &[]).expect("failed to resolve"),
+                vec![(source, Layer::Config)]
+            );
+        }
+
+        #[test]
+        fn parsing_format() {
+            assert_eq!("ini".parse::<SourceFormat>(), Ok(SourceFormat::Ini));
+            assert!("xml".parse::<SourceFormat>().is_err());
+        }
+    }
+}
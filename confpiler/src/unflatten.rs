@@ -0,0 +1,386 @@
+use serde_json::{Map, Number, Value};
+
+use crate::config::FlatConfigBuilder;
+use crate::error::{ConfpilerError, Result};
+use crate::keys::KeyFormat;
+use crate::provenance::ValueType;
+
+/// The document formats supported by [Unflattener::render].
+///
+/// Rendering YAML and TOML requires the `render-yaml` and `render-toml`
+/// features, respectively, which are enabled by default.
+#[derive(Debug, Clone, Copy, Default, Eq, PartialEq)]
+pub enum DocumentFormat {
+    #[default]
+    Yaml,
+    Toml,
+    Json,
+}
+
+impl DocumentFormat {
+    /// The string representations of the variants, as accepted by
+    /// [from_str](std::str::FromStr::from_str).
+    pub const VARIANTS: &'static [&'static str] = &["yaml", "toml", "json"];
+}
+
+impl std::str::FromStr for DocumentFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s {
+            "yaml" => Ok(Self::Yaml),
+            "toml" => Ok(Self::Toml),
+            "json" => Ok(Self::Json),
+            _ => Err(format!(
+                "unknown format \"{s}\" (expected one of: {})",
+                Self::VARIANTS.join(", ")
+            )),
+        }
+    }
+}
+
+/// Rebuilds nested documents from flattened keys, reversing the flattening
+/// performed by [FlatConfigBuilder::build].
+///
+/// Keys are split on the separator to form the nested structure, with each
+/// component formatted via the [KeyFormat] (converted to lowercase, by
+/// default). Values that would be flattened back into the same string are
+/// converted to booleans and numbers, while everything else is left as a
+/// string. This means that flattening the output with the same separator
+/// produces the original keys and values, so long as the original keys were
+/// uppercase.
+///
+/// Because arrays are flattened into joined strings or tables of indices
+/// (depending on the [ArrayStrategy](crate::ArrayStrategy)), they are never
+/// reconstructed.
+///
+/// # Examples
+/// ```
+/// use confpiler::{DocumentFormat, Unflattener};
+/// # use confpiler::error::ConfpilerError;
+/// # fn main() -> Result<(), ConfpilerError> {
+///
+/// let items = [("APP__DB__HOST", "localhost"), ("APP__DB__PORT", "5432")];
+///
+/// let json = Unflattener::default()
+///     .with_prefix("APP")
+///     .render(items, DocumentFormat::Json)?;
+///
+/// assert_eq!(
+///     json,
+///     "{\n  \"db\": {\n    \"host\": \"localhost\",\n    \"port\": 5432\n  }\n}\n"
+/// );
+/// # Ok(())
+/// # }
+/// ```
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct Unflattener {
+    prefix: Option<String>,
+    separator: String,
+    key_format: KeyFormat,
+}
+
+impl Unflattener {
+    /// Specifies a prefix that every key must start with (followed by the
+    /// separator).
+    ///
+    /// The prefix is removed before unflattening, and any key without the
+    /// prefix is ignored.
+    ///
+    /// # Examples
+    /// ```
+    /// use confpiler::Unflattener;
+    /// let mut unflattener = Unflattener::default();
+    /// unflattener.with_prefix("MYAPP"); // MYAPP__DB__HOST -> db: host:
+    /// ```
+    pub fn with_prefix(&mut self, prefix: &str) -> &mut Self {
+        self.prefix = Some(prefix.to_string());
+        self
+    }
+
+    /// Specifies the separator between the components of a key.
+    ///
+    /// The default is the same as the default for [FlatConfigBuilder].
+    ///
+    /// # Examples
+    /// ```
+    /// use confpiler::Unflattener;
+    /// let mut unflattener = Unflattener::default();
+    /// unflattener.with_separator("__"); // this is the default
+    /// ```
+    pub fn with_separator(&mut self, separator: &str) -> &mut Self {
+        self.separator = separator.to_string();
+        self
+    }
+
+    /// Specifies how each component of a key is formatted.
+    ///
+    /// The default is [KeyFormat::Lower].
+    ///
+    /// # Examples
+    /// ```
+    /// use confpiler::{KeyFormat, Unflattener};
+    /// let mut unflattener = Unflattener::default();
+    /// unflattener.with_key_format(KeyFormat::Preserve); // FOO__BAR -> FOO: BAR:
+    /// ```
+    pub fn with_key_format(&mut self, format: KeyFormat) -> &mut Self {
+        self.key_format = format;
+        self
+    }
+
+    /// Rebuild the nested structure of the given key/value pairs.
+    ///
+    /// This results in an error if a key is both a value and the parent of
+    /// other keys (like `FOO` and `FOO__BAR`).
+    pub fn unflatten<I, K, V>(&self, items: I) -> Result<Map<String, Value>>
+    where
+        I: IntoIterator<Item = (K, V)>,
+        K: AsRef<str>,
+        V: AsRef<str>,
+    {
+        let mut out = Map::new();
+
+        for (key, value) in items.into_iter() {
+            let key = key.as_ref();
+
+            let unprefixed = match self.prefix {
+                Some(ref prefix) => match key
+                    .strip_prefix(prefix.as_str())
+                    .and_then(|k| k.strip_prefix(self.separator.as_str()))
+                {
+                    Some(unprefixed) => unprefixed,
+                    None => continue,
+                },
+                None => key,
+            };
+
+            let mut components = unprefixed
+                .split(self.separator.as_str())
+                .map(|c| self.key_format.format(c))
+                .collect::<Vec<_>>();
+
+            // split always yields at least one component
+            let last = components.pop().unwrap_or_default();
+
+            let mut table = &mut out;
+            for component in components {
+                let entry = table
+                    .entry(component)
+                    .or_insert_with(|| Value::Object(Map::new()));
+
+                table = match entry {
+                    Value::Object(ref mut inner) => inner,
+                    _ => return Err(ConfpilerError::UnflattenConflict(key.to_string())),
+                };
+            }
+
+            if table.contains_key(&last) {
+                return Err(ConfpilerError::UnflattenConflict(key.to_string()));
+            }
+
+            table.insert(last, infer_value(value.as_ref()));
+        }
+
+        Ok(out)
+    }
+
+    /// Rebuild the nested structure of the given key/value pairs, rendering it
+    /// in the given format.
+    ///
+    /// See [unflatten](Unflattener::unflatten). This results in an error if
+    /// the feature required by the format is not enabled.
+    pub fn render<I, K, V>(&self, items: I, format: DocumentFormat) -> Result<String>
+    where
+        I: IntoIterator<Item = (K, V)>,
+        K: AsRef<str>,
+        V: AsRef<str>,
+    {
+        let document = Value::Object(self.unflatten(items)?);
+
+        match format {
+            #[cfg(feature = "render-yaml")]
+            DocumentFormat::Yaml => serde_yaml::to_string(&document)
+                .map_err(|e| ConfpilerError::SerializeError(e.to_string())),
+            #[cfg(not(feature = "render-yaml"))]
+            DocumentFormat::Yaml => Err(ConfpilerError::SerializeError(
+                "rendering YAML requires the \"render-yaml\" feature".to_string(),
+            )),
+            #[cfg(feature = "render-toml")]
+            DocumentFormat::Toml => {
+                // going through a toml::Value ensures values are emitted
+                // before tables, which toml requires
+                toml::Value::try_from(&document)
+                    .and_then(|v| toml::to_string(&v))
+                    .map_err(|e| ConfpilerError::SerializeError(e.to_string()))
+            }
+            #[cfg(not(feature = "render-toml"))]
+            DocumentFormat::Toml => Err(ConfpilerError::SerializeError(
+                "rendering TOML requires the \"render-toml\" feature".to_string(),
+            )),
+            DocumentFormat::Json => serde_json::to_string_pretty(&document)
+                .map(|s| s + "\n")
+                .map_err(|e| ConfpilerError::SerializeError(e.to_string())),
+        }
+    }
+}
+
+impl Default for Unflattener {
+    fn default() -> Self {
+        Self {
+            prefix: None,
+            separator: FlatConfigBuilder::DEFAULT_SEPARATOR.to_string(),
+            key_format: KeyFormat::Lower,
+        }
+    }
+}
+
+/// Convert a value to a boolean or number, but only if it would be flattened
+/// back into the exact same string.
+fn infer_value(value: &str) -> Value {
+    let inferred = match ValueType::infer(value) {
+        ValueType::Boolean => value.parse::<bool>().ok().map(Value::Bool),
+        ValueType::Integer => value.parse::<i64>().ok().map(|i| Value::Number(i.into())),
+        ValueType::Float => value
+            .parse::<f64>()
+            .ok()
+            .and_then(Number::from_f64)
+            .map(Value::Number),
+        _ => None,
+    };
+
+    inferred.unwrap_or_else(|| Value::String(value.to_string()))
+}
+
+#[cfg(test)]
+mod tests {
+    mod unflattener {
+        use super::super::*;
+        use crate::config::{flatten_into, FlattenOptions, Flattened};
+        use config::{Config, File, FileFormat};
+        use indexmap::IndexMap;
+
+        fn items() -> IndexMap<String, String> {
+            [
+                ("FOO", "10.2"),
+                ("BAR", "Hello"),
+                ("BAZ__HERP", "false"),
+                ("BAZ__DERP", "15"),
+                ("BAZ__HOOF__DOOF", "999"),
+                ("BIZ", "false,1111,Goodbye"),
+                ("PADDED", "007"),
+                ("WHOLE", "1.0"),
+                ("EMPTY", ""),
+            ]
+            .iter()
+            .map(|(k, v)| (k.to_string(), v.to_string()))
+            .collect()
+        }
+
+        fn round_trip(format: DocumentFormat, file_format: FileFormat) {
+            let input = items();
+            let rendered = Unflattener::default()
+                .render(&input, format)
+                .expect("failed to render");
+
+            let conf = Config::builder()
+                .add_source(File::from_str(&rendered, file_format))
+                .build()
+                .expect("failed to parse rendered output");
+
+            let mut out = Flattened::default();
+            flatten_into(
+                &conf.cache.into_table().expect("not a table"),
+                &mut out,
+                &FlattenOptions::default(),
+            )
+            .expect("failed to flatten");
+
+            assert_eq!(out.items, input);
+        }
+
+        #[test]
+        fn unflattening() {
+            let expected = serde_json::json!({
+                "foo": 10.2,
+                "bar": "Hello",
+                "baz": {
+                    "herp": false,
+                    "derp": 15,
+                    "hoof": {
+                        "doof": 999,
+                    },
+                },
+                "biz": "false,1111,Goodbye",
+                "padded": "007",
+                "whole": "1.0",
+                "empty": "",
+            });
+
+            let res = Unflattener::default()
+                .unflatten(&items())
+                .expect("failed to unflatten");
+
+            assert_eq!(Value::Object(res), expected);
+        }
+
+        #[test]
+        fn prefixes_and_separators() {
+            let input = [
+                ("APP_DB_HOST", "localhost"),
+                ("APP_PORT", "80"),
+                ("OTHER_KEY", "ignored"),
+                ("APP", "ignored"),
+            ];
+
+            let expected = serde_json::json!({
+                "DB": {
+                    "HOST": "localhost",
+                },
+                "PORT": 80,
+            });
+
+            let res = Unflattener::default()
+                .with_prefix("APP")
+                .with_separator("_")
+                .with_key_format(KeyFormat::Preserve)
+                .unflatten(input)
+                .expect("failed to unflatten");
+
+            assert_eq!(Value::Object(res), expected);
+        }
+
+        #[test]
+        fn conflicting_keys() {
+            let res = Unflattener::default().unflatten([("FOO", "1"), ("FOO__BAR", "2")]);
+
+            assert!(matches!(res, Err(ConfpilerError::UnflattenConflict(k)) if k == "FOO__BAR"));
+
+            let res = Unflattener::default().unflatten([("FOO__BAR", "2"), ("FOO", "1")]);
+
+            assert!(matches!(res, Err(ConfpilerError::UnflattenConflict(k)) if k == "FOO"));
+        }
+
+        #[test]
+        #[cfg(feature = "render-yaml")]
+        fn round_trips_yaml() {
+            round_trip(DocumentFormat::Yaml, FileFormat::Yaml);
+        }
+
+        #[test]
+        #[cfg(feature = "render-toml")]
+        fn round_trips_toml() {
+            round_trip(DocumentFormat::Toml, FileFormat::Toml);
+        }
+
+        #[test]
+        fn round_trips_json() {
+            round_trip(DocumentFormat::Json, FileFormat::Json);
+        }
+
+        #[test]
+        fn parsing_format() {
+            assert_eq!("toml".parse::<DocumentFormat>(), Ok(DocumentFormat::Toml));
+            assert!("xml".parse::<DocumentFormat>().is_err());
+        }
+    }
+}
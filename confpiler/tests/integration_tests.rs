@@ -1,11 +1,15 @@
-use confpiler::{FlatConfig, MergeWarning};
-use std::collections::HashMap;
+use confpiler::{
+    error::ConfpilerError, ArrayStrategy, DocumentFormat, FlatConfig, FlatConfigBuilder,
+    InvalidKeyPolicy, KeyDiff, KeyFormat, MergeWarning, NewKeyPolicy, Schema, SchemaViolation,
+    SourceFormat, TypeChangePolicy, Unflattener, ValueType,
+};
+use indexmap::IndexMap;
 
 // These are all effectively "happy path" tests that serve as smoke tests
 
 #[test]
 fn loading_single_file() {
-    let expected = HashMap::from([
+    let expected = IndexMap::from([
         ("FOO__BAR".to_string(), "10".to_string()),
         ("FOO__BAZ".to_string(), "99.9".to_string()),
         ("HOOF".to_string(), "true,false,hello".to_string()),
@@ -23,12 +27,16 @@ fn loading_single_file() {
 
     assert!(warnings.is_empty());
 
-    assert_eq!(config.items(), &expected);
+    // IndexMap equality ignores order, so compare the sequences
+    assert_eq!(
+        config.items().iter().collect::<Vec<_>>(),
+        expected.iter().collect::<Vec<_>>()
+    );
 }
 
 #[test]
 fn specifying_separators() {
-    let expected = HashMap::from([
+    let expected = IndexMap::from([
         ("FOO_BAR".to_string(), "10".to_string()),
         ("FOO_BAZ".to_string(), "99.9".to_string()),
         ("HOOF".to_string(), "true false hello".to_string()),
@@ -47,12 +55,16 @@ fn specifying_separators() {
         .expect("Failed to construct config");
 
     assert!(warnings.is_empty());
-    assert_eq!(config.items(), &expected);
+    // IndexMap equality ignores order, so compare the sequences
+    assert_eq!(
+        config.items().iter().collect::<Vec<_>>(),
+        expected.iter().collect::<Vec<_>>()
+    );
 }
 
 #[test]
 fn multiple_files() {
-    let expected = HashMap::from([
+    let expected = IndexMap::from([
         ("FOO__BAR".to_string(), "10".to_string()),
         ("FOO__BAZ".to_string(), "222.2".to_string()),
         (
@@ -75,12 +87,16 @@ fn multiple_files() {
         .expect("Failed to construct config");
 
     assert!(warnings.is_empty());
-    assert_eq!(config.items(), &expected);
+    // IndexMap equality ignores order, so compare the sequences
+    assert_eq!(
+        config.items().iter().collect::<Vec<_>>(),
+        expected.iter().collect::<Vec<_>>()
+    );
 }
 
 #[test]
 fn generating_warnings() {
-    let expected = HashMap::from([
+    let expected = IndexMap::from([
         ("FOO__BAR".to_string(), "10".to_string()),
         ("FOO__BAZ".to_string(), "333.3".to_string()),
         ("HOOF".to_string(), "true,false,hello".to_string()),
@@ -112,7 +128,11 @@ fn generating_warnings() {
         value: "goodbye".to_string(),
     }));
 
-    assert_eq!(config.items(), &expected);
+    // IndexMap equality ignores order, so compare the sequences
+    assert_eq!(
+        config.items().iter().collect::<Vec<_>>(),
+        expected.iter().collect::<Vec<_>>()
+    );
 }
 
 #[test]
@@ -129,3 +149,1096 @@ fn invalid_configurations() {
 
     assert!(res.is_err());
 }
+
+#[test]
+fn tracking_provenance() {
+    let (config, _) = FlatConfig::builder()
+        .add_config("tests/fixtures/file_one")
+        .add_config("tests/fixtures/file_two_warnings")
+        .add_config("tests/fixtures/file_three_warnings")
+        .build()
+        .expect("Failed to construct config");
+
+    assert_eq!(config.origin_of("HOOF"), Some("tests/fixtures/file_one"));
+    assert_eq!(
+        config.origin_of("FOO__BAZ"),
+        Some("tests/fixtures/file_two_warnings")
+    );
+
+    let provenance = config
+        .provenance("DOOF__HERP__DERP")
+        .expect("missing provenance");
+
+    assert_eq!(
+        provenance.origins().collect::<Vec<_>>(),
+        vec![
+            "tests/fixtures/file_one",
+            "tests/fixtures/file_three_warnings"
+        ]
+    );
+    assert!(provenance
+        .assignments()
+        .iter()
+        .all(|a| a.value() == "goodbye"));
+}
+
+#[test]
+fn generating_shadowed_override_warnings() {
+    // overriding a value that was itself an override is normal layering
+    let (config, warnings) = FlatConfig::builder()
+        .add_config("tests/fixtures/file_one")
+        .add_config("tests/fixtures/file_two")
+        .add_config("tests/fixtures/file_four_shadows")
+        .build()
+        .expect("Failed to construct config");
+
+    assert!(warnings.is_empty());
+    assert_eq!(config.items().get("FOO__BAZ"), Some(&"444.4".to_string()));
+
+    // but the default of a later directory replacing an environment value
+    // means that value is never used
+    let (config, warnings) = FlatConfig::builder()
+        .add_directory("tests/fixtures/layered")
+        .add_directory("tests/fixtures/layered_shadows")
+        .with_environment("production")
+        .build()
+        .expect("Failed to construct config");
+
+    assert_eq!(
+        warnings,
+        vec![MergeWarning::ShadowedOverride {
+            origin: "tests/fixtures/layered/production".to_string(),
+            overrider: "tests/fixtures/layered_shadows/default".to_string(),
+            key: "LOG_LEVEL".to_string(),
+            value: "warn".to_string(),
+        }]
+    );
+
+    assert_eq!(config.items().get("LOG_LEVEL"), Some(&"error".to_string()));
+}
+
+#[test]
+fn generating_new_key_warnings() {
+    let (_, warnings) = FlatConfig::builder()
+        .add_config("tests/fixtures/file_one")
+        .add_config("tests/fixtures/file_two")
+        .build()
+        .expect("Failed to construct config");
+
+    // this is opt-in
+    assert!(warnings.is_empty());
+
+    let (_, warnings) = FlatConfig::builder()
+        .add_config("tests/fixtures/file_one")
+        .add_config("tests/fixtures/file_two")
+        .with_new_key_policy(NewKeyPolicy::Warn)
+        .build()
+        .expect("Failed to construct config");
+
+    assert_eq!(
+        warnings,
+        vec![MergeWarning::NewKey {
+            origin: "tests/fixtures/file_two".to_string(),
+            key: "ANOTHER".to_string(),
+        }]
+    );
+
+    let res = FlatConfig::builder()
+        .add_config("tests/fixtures/file_one")
+        .add_config("tests/fixtures/file_two")
+        .with_new_key_policy(NewKeyPolicy::Deny)
+        .build();
+
+    match res {
+        Err(ConfpilerError::Warnings(warnings)) => assert_eq!(warnings.len(), 1),
+        _ => panic!("expected an error"),
+    }
+
+    // setting a key that an earlier config deleted doesn't make it new
+    let (config, warnings) = FlatConfig::builder()
+        .add_config("tests/fixtures/file_one")
+        .add_config("tests/fixtures/file_eleven_nulls")
+        .add_config("tests/fixtures/file_eighteen_restores_deleted")
+        .with_null_deletes(true)
+        .with_new_key_policy(NewKeyPolicy::Deny)
+        .build()
+        .expect("Failed to construct config");
+
+    assert!(!warnings
+        .iter()
+        .any(|w| matches!(w, MergeWarning::NewKey { .. })));
+    assert_eq!(config.items().get("FOO__BAR"), Some(&"30".to_string()));
+}
+
+#[test]
+fn generating_type_changed_warnings() {
+    let (_, warnings) = FlatConfig::builder()
+        .add_config("tests/fixtures/file_one")
+        .add_config("tests/fixtures/file_five_type_changes")
+        .build()
+        .expect("Failed to construct config");
+
+    assert_eq!(warnings.len(), 2);
+
+    assert!(warnings.contains(&MergeWarning::TypeChanged {
+        origin: "tests/fixtures/file_one".to_string(),
+        overrider: "tests/fixtures/file_five_type_changes".to_string(),
+        key: "FOO__BAR".to_string(),
+        from: ValueType::Integer,
+        to: ValueType::String,
+    }));
+
+    assert!(warnings.contains(&MergeWarning::TypeChanged {
+        origin: "tests/fixtures/file_one".to_string(),
+        overrider: "tests/fixtures/file_five_type_changes".to_string(),
+        key: "HOOF".to_string(),
+        from: ValueType::Array,
+        to: ValueType::Boolean,
+    }));
+
+    let (_, warnings) = FlatConfig::builder()
+        .add_config("tests/fixtures/file_one")
+        .add_config("tests/fixtures/file_five_type_changes")
+        .with_type_change_policy(TypeChangePolicy::Allow)
+        .build()
+        .expect("Failed to construct config");
+
+    assert!(warnings.is_empty());
+
+    let res = FlatConfig::builder()
+        .add_config("tests/fixtures/file_one")
+        .add_config("tests/fixtures/file_five_type_changes")
+        .with_type_change_policy(TypeChangePolicy::Deny)
+        .build();
+
+    assert!(matches!(res, Err(ConfpilerError::Warnings(w)) if w.len() == 2));
+}
+
+#[test]
+fn preserving_key_order() {
+    let (config, _) = FlatConfig::builder()
+        .add_config("tests/fixtures/file_one")
+        .add_config("tests/fixtures/file_two")
+        .add_config("tests/fixtures/file_three")
+        .build()
+        .expect("Failed to construct config");
+
+    // overridden keys keep their original position, new keys are appended
+    assert_eq!(
+        config.items().keys().collect::<Vec<_>>(),
+        vec![
+            "FOO__BAR",
+            "FOO__BAZ",
+            "HOOF",
+            "DOOF__HERP__DERP",
+            "UNDER_SCORED__KEY",
+            "ANOTHER",
+        ]
+    );
+}
+
+#[test]
+fn json_encoded_values() {
+    let (config, _) = FlatConfig::builder()
+        .add_config("tests/fixtures/invalid_array")
+        .with_json_arrays(true)
+        .build()
+        .expect("Failed to construct config");
+
+    assert_eq!(
+        config.items().get("FOO"),
+        Some(&r#"["this array",{"bar":"has a nested value"}]"#.to_string())
+    );
+
+    let (config, _) = FlatConfig::builder()
+        .add_config("tests/fixtures/file_one")
+        .with_json_key("DOOF")
+        .build()
+        .expect("Failed to construct config");
+
+    assert_eq!(
+        config.items().get("DOOF"),
+        Some(&r#"{"herp":{"derp":"goodbye"}}"#.to_string())
+    );
+    assert!(!config.items().contains_key("DOOF__HERP__DERP"));
+}
+
+#[test]
+fn replacing_json_encoded_tables() {
+    let (config, warnings) = FlatConfig::builder()
+        .add_config_str("first", "foo:\n  bar: 1\n", SourceFormat::Yaml)
+        .add_config_str("second", "foo: plain\n", SourceFormat::Yaml)
+        .with_json_key("FOO")
+        .build()
+        .expect("Failed to construct config");
+
+    assert_eq!(config.items().get("FOO"), Some(&"plain".to_string()));
+    assert_eq!(
+        warnings,
+        vec![MergeWarning::TypeChanged {
+            origin: "first".to_string(),
+            overrider: "second".to_string(),
+            key: "FOO".to_string(),
+            from: ValueType::Table,
+            to: ValueType::String,
+        }]
+    );
+}
+
+#[test]
+fn indexed_arrays() {
+    let (config, warnings) = FlatConfig::builder()
+        .add_config("tests/fixtures/file_one")
+        .add_config("tests/fixtures/file_three")
+        .with_array_strategy(ArrayStrategy::Indexed)
+        .with_array_length_suffix("LEN")
+        .with_new_key_policy(NewKeyPolicy::Warn)
+        .build()
+        .expect("Failed to construct config");
+
+    // the array got longer, but that doesn't mean the elements are new keys
+    assert!(!warnings
+        .iter()
+        .any(|w| matches!(w, MergeWarning::NewKey { .. })));
+
+    assert_eq!(config.items().get("HOOF__0"), Some(&"arrays".to_string()));
+    assert_eq!(config.items().get("HOOF__4"), Some(&"merged".to_string()));
+    assert_eq!(config.items().get("HOOF__LEN"), Some(&"5".to_string()));
+    assert!(!config.items().contains_key("HOOF"));
+}
+
+#[test]
+fn indexed_arrays_are_replaced_not_merged() {
+    let (config, _) = FlatConfig::builder()
+        .add_config("tests/fixtures/file_one")
+        .add_config("tests/fixtures/file_six_short_array")
+        .with_array_strategy(ArrayStrategy::Indexed)
+        .build()
+        .expect("Failed to construct config");
+
+    assert_eq!(config.items().get("HOOF__0"), Some(&"only".to_string()));
+    assert!(!config.items().contains_key("HOOF__1"));
+    assert!(!config.items().contains_key("HOOF__2"));
+
+    // the removed elements are recorded as deleted by the shorter array
+    let provenance = config.provenance("HOOF__1").expect("missing provenance");
+    assert!(provenance.is_deleted());
+    assert_eq!(
+        provenance.origins().collect::<Vec<_>>(),
+        vec![
+            "tests/fixtures/file_one",
+            "tests/fixtures/file_six_short_array"
+        ]
+    );
+
+    let mut deleted = config.deleted_keys().collect::<Vec<_>>();
+    deleted.sort();
+    assert_eq!(deleted, vec!["HOOF__1", "HOOF__2"]);
+}
+
+#[test]
+fn indexed_arrays_replaced_by_other_values() {
+    // replacing an array with a scalar removes every element
+    let (config, _) = FlatConfig::builder()
+        .add_config("tests/fixtures/file_one")
+        .add_config("tests/fixtures/file_sixteen_array_to_scalar")
+        .with_array_strategy(ArrayStrategy::Indexed)
+        .build()
+        .expect("Failed to construct config");
+
+    assert_eq!(config.items().get("HOOF"), Some(&"replaced".to_string()));
+
+    let mut deleted = config.deleted_keys().collect::<Vec<_>>();
+    deleted.sort();
+    assert_eq!(deleted, vec!["HOOF__0", "HOOF__1", "HOOF__2"]);
+
+    let provenance = config.provenance("HOOF__2").expect("missing provenance");
+    assert_eq!(
+        provenance.origin(),
+        "tests/fixtures/file_sixteen_array_to_scalar"
+    );
+
+    // replacing an array with a table removes every element the table
+    // doesn't set again
+    let (config, _) = FlatConfig::builder()
+        .add_config("tests/fixtures/file_one")
+        .add_config("tests/fixtures/file_seventeen_array_to_table")
+        .with_array_strategy(ArrayStrategy::Indexed)
+        .build()
+        .expect("Failed to construct config");
+
+    assert_eq!(config.items().get("HOOF__0"), Some(&"zero".to_string()));
+    assert_eq!(config.items().get("HOOF__NAME"), Some(&"table".to_string()));
+
+    let mut deleted = config.deleted_keys().collect::<Vec<_>>();
+    deleted.sort();
+    assert_eq!(deleted, vec!["HOOF__1", "HOOF__2"]);
+}
+
+#[test]
+fn specifying_key_format() {
+    let expected = IndexMap::from([
+        ("foo.bar".to_string(), "10".to_string()),
+        ("foo.baz".to_string(), "99.9".to_string()),
+        ("hoof".to_string(), "true,false,hello".to_string()),
+        ("doof.herp.derp".to_string(), "goodbye".to_string()),
+        (
+            "under_scored.key".to_string(),
+            "https://foo.bar".to_string(),
+        ),
+    ]);
+
+    let (config, warnings) = FlatConfig::builder()
+        .add_config("tests/fixtures/file_one")
+        .with_key_format(KeyFormat::Dotted)
+        .build()
+        .expect("Failed to construct config");
+
+    assert!(warnings.is_empty());
+    assert_eq!(config.items(), &expected);
+}
+
+#[test]
+fn invalid_key_policies() {
+    // by default, invalid keys are allowed
+    let (config, _) = FlatConfig::builder()
+        .add_config("tests/fixtures/invalid_names")
+        .build()
+        .expect("Failed to construct config");
+
+    assert!(config.items().contains_key("MY-APP__API.URL"));
+    assert!(config.items().contains_key("1PASSWORD"));
+
+    let (config, _) = FlatConfig::builder()
+        .add_config("tests/fixtures/invalid_names")
+        .with_invalid_key_policy(InvalidKeyPolicy::Sanitize)
+        .build()
+        .expect("Failed to construct config");
+
+    let expected = IndexMap::from([
+        ("MY_APP__API_URL".to_string(), "https://foo.bar".to_string()),
+        ("MY_APP__RETRIES".to_string(), "3".to_string()),
+        ("_1PASSWORD".to_string(), "true".to_string()),
+    ]);
+
+    assert_eq!(config.items(), &expected);
+    assert!(config.provenance("MY_APP__API_URL").is_some());
+
+    let res = FlatConfig::builder()
+        .add_config("tests/fixtures/invalid_names")
+        .with_invalid_key_policy(InvalidKeyPolicy::Error)
+        .build();
+
+    match res {
+        Err(ConfpilerError::InvalidKey {
+            key,
+            source_key,
+            origin,
+        }) => {
+            assert_eq!(key, "MY-APP__API.URL");
+            assert_eq!(source_key, "my-app.api.url");
+            assert_eq!(origin, "tests/fixtures/invalid_names");
+        }
+        _ => panic!("expected an invalid key error"),
+    }
+}
+
+#[test]
+fn interpolating_references() {
+    let (config, _) = FlatConfig::builder()
+        .add_config("tests/fixtures/file_seven_references")
+        .add_config("tests/fixtures/file_eight_reference_override")
+        .with_interpolation(true)
+        .build()
+        .expect("Failed to construct config");
+
+    // the override of the host flows into the url
+    assert_eq!(
+        config.items().get("DB__URL"),
+        Some(&"postgres://db.example.com:5432/app".to_string())
+    );
+
+    // without opting in, the references are left alone
+    let (config, _) = FlatConfig::builder()
+        .add_config("tests/fixtures/file_seven_references")
+        .build()
+        .expect("Failed to construct config");
+
+    assert_eq!(
+        config.items().get("DB__URL"),
+        Some(&"postgres://${DB__HOST}:${DB__PORT}/app".to_string())
+    );
+
+    let res = FlatConfig::builder()
+        .add_config("tests/fixtures/file_nine_bad_references")
+        .with_interpolation(true)
+        .build();
+
+    match res {
+        Err(ConfpilerError::ReferenceCycle(chain)) => {
+            assert_eq!(chain, vec!["FOO", "BAR", "FOO"]);
+        }
+        _ => panic!("expected a reference cycle"),
+    }
+}
+
+#[test]
+fn interpolating_environment_variables() {
+    std::env::set_var("CONFPILER_TEST_GIT_SHA", "deadbeef");
+    std::env::remove_var("CONFPILER_TEST_REGION");
+
+    let (config, _) = FlatConfig::builder()
+        .add_config("tests/fixtures/file_ten_env_references")
+        .with_env_interpolation(true)
+        .build()
+        .expect("Failed to construct config");
+
+    assert_eq!(
+        config.items().get("BUILD__SHA"),
+        Some(&"deadbeef".to_string())
+    );
+    assert_eq!(
+        config.items().get("BUILD__REGION"),
+        Some(&"us-east-1".to_string())
+    );
+
+    // without opting in, the references are left alone
+    let (config, _) = FlatConfig::builder()
+        .add_config("tests/fixtures/file_ten_env_references")
+        .build()
+        .expect("Failed to construct config");
+
+    assert_eq!(
+        config.items().get("BUILD__SHA"),
+        Some(&"${env:CONFPILER_TEST_GIT_SHA}".to_string())
+    );
+}
+
+#[test]
+fn deleting_keys_with_nulls() {
+    let expected = IndexMap::from([
+        ("FOO__BAZ".to_string(), "99.9".to_string()),
+        ("HOOF".to_string(), "true,false,hello".to_string()),
+        (
+            "UNDER_SCORED__KEY".to_string(),
+            "https://foo.bar".to_string(),
+        ),
+    ]);
+
+    let (config, warnings) = FlatConfig::builder()
+        .add_config("tests/fixtures/file_one")
+        .add_config("tests/fixtures/file_eleven_nulls")
+        .with_null_deletes(true)
+        .build()
+        .expect("Failed to construct config");
+
+    assert_eq!(config.items(), &expected);
+    assert_eq!(
+        warnings,
+        vec![MergeWarning::NothingDeleted {
+            origin: "tests/fixtures/file_eleven_nulls".to_string(),
+            key: "NONEXISTENT".to_string(),
+        }]
+    );
+
+    // deleting a map deletes everything beneath it
+    let provenance = config
+        .provenance("DOOF__HERP__DERP")
+        .expect("missing provenance");
+
+    assert!(provenance.is_deleted());
+    assert_eq!(provenance.origin(), "tests/fixtures/file_eleven_nulls");
+    assert_eq!(provenance.value_type(), ValueType::Null);
+    assert!(config.origin_of("DOOF__HERP__DERP").is_none());
+
+    let mut deleted = config.deleted_keys().collect::<Vec<_>>();
+    deleted.sort();
+    assert_eq!(deleted, vec!["DOOF__HERP__DERP", "FOO__BAR"]);
+
+    // by default, nulls are ignored
+    let (config, warnings) = FlatConfig::builder()
+        .add_config("tests/fixtures/file_one")
+        .add_config("tests/fixtures/file_eleven_nulls")
+        .build()
+        .expect("Failed to construct config");
+
+    assert!(warnings.is_empty());
+    assert!(config.items().contains_key("FOO__BAR"));
+    assert_eq!(config.deleted_keys().count(), 0);
+}
+
+#[test]
+fn deleting_keys_with_nulls_spares_siblings() {
+    let (config, warnings) = FlatConfig::builder()
+        .add_config("tests/fixtures/file_thirteen_siblings")
+        .add_config("tests/fixtures/file_fourteen_sibling_nulls")
+        .with_array_strategy(ArrayStrategy::Indexed)
+        .with_index_separator("_")
+        .with_null_deletes(true)
+        .build()
+        .expect("Failed to construct config");
+
+    assert!(warnings.is_empty());
+
+    // FOO_BAR is a sibling of FOO, not an element of it, and HOOF_BAR is a
+    // sibling of the HOOF array
+    assert_eq!(
+        config.items(),
+        &IndexMap::from([
+            ("FOO_BAR".to_string(), "2".to_string()),
+            ("HOOF_BAR".to_string(), "3".to_string()),
+        ])
+    );
+
+    // but the elements of the array are deleted with it
+    let mut deleted = config.deleted_keys().collect::<Vec<_>>();
+    deleted.sort();
+    assert_eq!(deleted, vec!["FOO", "HOOF_0", "HOOF_1"]);
+}
+
+#[test]
+fn deleting_arrays_deletes_their_length() {
+    for (strategy, expected) in [
+        (ArrayStrategy::Joined, vec!["HOOF", "HOOF_LEN"]),
+        (ArrayStrategy::Indexed, vec!["HOOF_0", "HOOF_1", "HOOF_LEN"]),
+    ] {
+        let (config, warnings) = FlatConfig::builder()
+            .add_config("tests/fixtures/file_thirteen_siblings")
+            .add_config("tests/fixtures/file_fourteen_sibling_nulls")
+            .with_array_strategy(strategy)
+            .with_index_separator("_")
+            .with_array_length_suffix("LEN")
+            .with_null_deletes(true)
+            .build()
+            .expect("Failed to construct config");
+
+        assert!(warnings.is_empty());
+        assert!(!config.items().keys().any(|k| k.starts_with("HOOF_L")));
+
+        let mut deleted = config
+            .deleted_keys()
+            .filter(|k| k.starts_with("HOOF"))
+            .collect::<Vec<_>>();
+        deleted.sort();
+        assert_eq!(deleted, expected, "{strategy:?}");
+    }
+}
+
+/// The formats [Unflattener::render] supports with the enabled features.
+fn rendered_formats() -> Vec<(DocumentFormat, &'static str)> {
+    [
+        (DocumentFormat::Json, "json", true),
+        (DocumentFormat::Yaml, "yaml", cfg!(feature = "render-yaml")),
+        (DocumentFormat::Toml, "toml", cfg!(feature = "render-toml")),
+    ]
+    .into_iter()
+    .filter(|(_, _, enabled)| *enabled)
+    .map(|(format, ext, _)| (format, ext))
+    .collect()
+}
+
+/// Render the config built from `paths` in every format, asserting that
+/// building the rendered document with the same settings produces the same
+/// config.
+fn assert_round_trips<F>(name: &str, paths: &[&str], configure: F, unflattener: &Unflattener)
+where
+    F: Fn(&mut FlatConfigBuilder),
+{
+    let mut builder = FlatConfig::builder();
+    for path in paths {
+        builder.add_config(path);
+    }
+    configure(&mut builder);
+    let (config, _) = builder.build().expect("Failed to construct config");
+
+    let dir = std::env::temp_dir().join(format!(
+        "confpiler-round-trip-{name}-{}",
+        std::process::id()
+    ));
+    std::fs::create_dir_all(&dir).expect("could not create temp dir");
+
+    for (format, ext) in rendered_formats() {
+        let rendered = unflattener
+            .render(config.items(), format)
+            .expect("Failed to render config");
+
+        let file = dir.join(format!("unflattened.{ext}"));
+        std::fs::write(&file, rendered).expect("could not write temp file");
+
+        let mut builder = FlatConfig::builder();
+        configure(builder.add_config(file.to_str().expect("invalid path")));
+        let (round_tripped, _) = builder.build().expect("Failed to construct config");
+
+        assert_eq!(round_tripped.items(), config.items(), "{name} ({ext})");
+    }
+
+    std::fs::remove_dir_all(&dir).expect("could not remove temp dir");
+}
+
+#[test]
+fn unflattening_round_trips() {
+    assert_round_trips(
+        "default",
+        &["tests/fixtures/file_one", "tests/fixtures/file_two"],
+        |_| {},
+        &Unflattener::default(),
+    );
+}
+
+#[test]
+fn unflattening_round_trips_arrays() {
+    let path = "tests/fixtures/file_fifteen_round_trip";
+
+    // joined arrays are rendered as strings, which flatten back unchanged
+    assert_round_trips(
+        "joined",
+        &[path],
+        |b| {
+            b.with_json_key("EXTRA");
+        },
+        &Unflattener::default(),
+    );
+
+    // indexed arrays are rendered as tables of indices, which flatten back
+    // into the same keys
+    assert_round_trips(
+        "indexed",
+        &[path],
+        |b| {
+            b.with_json_key("EXTRA")
+                .with_array_strategy(ArrayStrategy::Indexed)
+                .with_array_length_suffix("LEN");
+        },
+        &Unflattener::default(),
+    );
+}
+
+#[test]
+fn unflattening_round_trips_json_encoded_subtrees() {
+    let path = "tests/fixtures/file_fifteen_round_trip";
+
+    let (config, _) = FlatConfig::builder()
+        .add_config(path)
+        .with_json_key("EXTRA")
+        .build()
+        .expect("Failed to construct config");
+
+    assert_eq!(
+        config.items().get("EXTRA"),
+        Some(&r#"{"nested":{"key":"value"},"list":[1,2]}"#.to_string())
+    );
+
+    // the encoded subtree is rendered as a string, not unflattened
+    let unflattened = Unflattener::default()
+        .unflatten(config.items())
+        .expect("Failed to unflatten config");
+    assert!(unflattened["extra"].is_string());
+
+    assert_round_trips(
+        "json",
+        &[path],
+        |b| {
+            b.with_json_key("EXTRA");
+        },
+        &Unflattener::default(),
+    );
+}
+
+#[test]
+fn unflattening_round_trips_custom_separators() {
+    let path = "tests/fixtures/file_fifteen_round_trip";
+    let mut unflattener = Unflattener::default();
+    unflattener.with_separator("_");
+
+    assert_round_trips(
+        "separator-joined",
+        &[path],
+        |b| {
+            b.with_json_key("EXTRA").with_separator("_");
+        },
+        &unflattener,
+    );
+
+    assert_round_trips(
+        "separator-indexed",
+        &[path],
+        |b| {
+            b.with_json_key("EXTRA")
+                .with_separator("_")
+                .with_array_strategy(ArrayStrategy::Indexed)
+                .with_index_separator("_");
+        },
+        &unflattener,
+    );
+}
+
+#[test]
+fn layering_dotenv_files() {
+    let expected = IndexMap::from([
+        ("FOO__BAR".to_string(), "20".to_string()),
+        ("FOO__BAZ".to_string(), "99.9".to_string()),
+        ("HOOF".to_string(), "true,false,hello".to_string()),
+        (
+            "DOOF__HERP__DERP".to_string(),
+            "hello \"world\"".to_string(),
+        ),
+        (
+            "UNDER_SCORED__KEY".to_string(),
+            "https://foo.bar".to_string(),
+        ),
+        (
+            "CERT".to_string(),
+            "-----BEGIN-----\nabc\n-----END-----".to_string(),
+        ),
+    ]);
+
+    // dotenv files may be specified with or without the extension
+    for legacy in ["tests/fixtures/legacy.env", "tests/fixtures/legacy"] {
+        let (config, warnings) = FlatConfig::builder()
+            .add_config("tests/fixtures/file_one")
+            .add_config(legacy)
+            .build()
+            .expect("Failed to construct config");
+
+        // types are inferred, so overriding integers doesn't change the type
+        assert!(warnings.is_empty());
+        assert_eq!(config.items(), &expected);
+        assert_eq!(config.origin_of("FOO__BAR"), Some(legacy));
+    }
+}
+
+#[test]
+fn dotenv_configs_must_not_be_ambiguous() {
+    // neither of ambiguous.env and ambiguous.yaml takes precedence
+    let res = FlatConfig::builder()
+        .add_config("tests/fixtures/ambiguous")
+        .build();
+
+    match res {
+        Err(ConfpilerError::AmbiguousConfig { path, candidates }) => {
+            assert_eq!(path, "tests/fixtures/ambiguous");
+            assert_eq!(
+                candidates,
+                vec![
+                    "tests/fixtures/ambiguous.env".to_string(),
+                    "tests/fixtures/ambiguous.yaml".to_string(),
+                ]
+            );
+        }
+        _ => panic!("expected an ambiguous config error"),
+    }
+
+    // but either can be specified with its extension
+    for (path, expected) in [
+        ("tests/fixtures/ambiguous.env", "1"),
+        ("tests/fixtures/ambiguous.yaml", "2"),
+    ] {
+        let (config, _) = FlatConfig::builder()
+            .add_config(path)
+            .build()
+            .expect("Failed to construct config");
+
+        assert_eq!(config.items().get("FOO__BAR"), Some(&expected.to_string()));
+    }
+}
+
+#[test]
+fn layering_in_memory_configs() {
+    let expected = IndexMap::from([
+        ("FOO__BAR".to_string(), "11".to_string()),
+        ("FOO__BAZ".to_string(), "99.9".to_string()),
+        ("HOOF".to_string(), "true,false,hello".to_string()),
+        ("DOOF__HERP__DERP".to_string(), "hello".to_string()),
+        (
+            "UNDER_SCORED__KEY".to_string(),
+            "https://foo.bar".to_string(),
+        ),
+    ]);
+
+    let value = serde_json::json!({
+        "doof": {
+            "herp": {
+                "derp": "hello",
+            },
+        },
+    });
+
+    let (config, warnings) = FlatConfig::builder()
+        .add_config("tests/fixtures/file_one")
+        .add_config_str(
+            "overrides",
+            "[foo]\nbar = 11\nbaz = 99.9\n",
+            SourceFormat::Toml,
+        )
+        .add_config_value("generated", value.as_object().cloned().unwrap())
+        .build()
+        .expect("Failed to construct config");
+
+    assert_eq!(config.items(), &expected);
+    assert_eq!(config.origin_of("FOO__BAR"), Some("overrides"));
+    assert_eq!(config.origin_of("DOOF__HERP__DERP"), Some("generated"));
+    assert_eq!(
+        warnings,
+        vec![MergeWarning::RedundantValue {
+            overrider: "overrides".to_string(),
+            key: "FOO__BAZ".to_string(),
+            value: "99.9".to_string(),
+        }]
+    );
+
+    // origins must be unique, just like paths
+    let res = FlatConfig::builder()
+        .add_config_str("same", "FOO=1", SourceFormat::Dotenv)
+        .add_config_str("same", "FOO=2", SourceFormat::Dotenv)
+        .build();
+
+    assert!(matches!(res, Err(ConfpilerError::DuplicateConfig(origin)) if origin == "same"));
+}
+
+#[test]
+fn diffing_configs() {
+    let (staging, _) = FlatConfig::builder()
+        .add_config("tests/fixtures/file_one")
+        .build()
+        .expect("Failed to construct config");
+
+    let (production, _) = FlatConfig::builder()
+        .add_config("tests/fixtures/file_one")
+        .add_config("tests/fixtures/file_two")
+        .build()
+        .expect("Failed to construct config");
+
+    let diff = staging.diff(&production);
+
+    assert!(!diff.is_empty());
+    for change in diff.iter() {
+        match change {
+            KeyDiff::Changed {
+                key,
+                old_value,
+                new_value,
+                old_origin,
+                new_origin,
+            } => {
+                assert_eq!(staging.items().get(key), Some(old_value));
+                assert_eq!(production.items().get(key), Some(new_value));
+                assert_eq!(old_origin.as_deref(), Some("tests/fixtures/file_one"));
+                assert_eq!(new_origin.as_deref(), Some("tests/fixtures/file_two"));
+            }
+            KeyDiff::Added { key, origin, .. } => {
+                assert!(!staging.items().contains_key(key));
+                assert_eq!(origin.as_deref(), Some("tests/fixtures/file_two"));
+            }
+            KeyDiff::Removed { .. } => panic!("nothing can be removed by merging"),
+        }
+    }
+
+    // diffing is symmetric
+    assert_eq!(production.diff(&staging).len(), diff.len());
+}
+
+#[test]
+fn validating_against_a_schema() {
+    let schema = Schema::from_file("tests/fixtures/schema.yaml").expect("Failed to load schema");
+
+    let (config, _) = FlatConfig::builder()
+        .add_config("tests/fixtures/file_one")
+        .with_schema(schema.clone())
+        .build()
+        .expect("Failed to construct config");
+
+    assert!(schema.validate(&config).is_empty());
+
+    // file_two sets FOO__BAZ out of range
+    let res = FlatConfig::builder()
+        .add_config("tests/fixtures/file_one")
+        .add_config("tests/fixtures/file_two")
+        .with_schema(schema)
+        .build();
+
+    match res {
+        Err(ConfpilerError::SchemaViolations(violations)) => {
+            assert_eq!(
+                violations,
+                vec![SchemaViolation::Invalid {
+                    key: "FOO__BAZ".to_string(),
+                    value: "222.2".to_string(),
+                    origin: Some("tests/fixtures/file_two".to_string()),
+                    expected: "a number of at most 100".to_string(),
+                }]
+            );
+        }
+        _ => panic!("expected schema violations"),
+    }
+}
+
+#[test]
+fn requiring_placeholders_be_overridden() {
+    let res = FlatConfig::builder()
+        .add_config("tests/fixtures/file_twelve_placeholders")
+        .add_config("tests/fixtures/file_two")
+        .with_required_marker("CHANGEME")
+        .build();
+
+    match res {
+        Err(ConfpilerError::UnresolvedPlaceholders(keys)) => {
+            assert_eq!(keys, vec!["DB__PASSWORD", "API_KEY"]);
+        }
+        _ => panic!("expected unresolved placeholders"),
+    }
+
+    // without a marker, the placeholder is just another value
+    let (config, _) = FlatConfig::builder()
+        .add_config("tests/fixtures/file_twelve_placeholders")
+        .add_config("tests/fixtures/file_two")
+        .build()
+        .expect("Failed to construct config");
+
+    assert_eq!(config.items().get("FOO__BAZ"), Some(&"222.2".to_string()));
+    assert_eq!(
+        config.items().get("DB__PASSWORD"),
+        Some(&"CHANGEME".to_string())
+    );
+}
+
+#[test]
+fn layering_directories() {
+    let (config, _) = FlatConfig::builder()
+        .add_config("tests/fixtures/file_two")
+        .add_directory("tests/fixtures/layered")
+        .with_environment("production")
+        .with_environment("production-east")
+        .build()
+        .expect("Failed to construct config");
+
+    let expected = IndexMap::from([
+        ("FOO__BAZ".to_string(), "222.2".to_string()),
+        ("ANOTHER".to_string(), "one".to_string()),
+        ("DB__HOST".to_string(), "east.prod.db.internal".to_string()),
+        ("DB__PORT".to_string(), "5432".to_string()),
+        ("LOG_LEVEL".to_string(), "warn".to_string()),
+    ]);
+
+    assert_eq!(config.items(), &expected);
+    assert_eq!(
+        config.origin_of("LOG_LEVEL"),
+        Some("tests/fixtures/layered/production")
+    );
+
+    // environments without a config are ignored
+    let (config, _) = FlatConfig::builder()
+        .add_directory("tests/fixtures/layered")
+        .with_environment("development")
+        .build()
+        .expect("Failed to construct config");
+
+    assert_eq!(
+        config.items().get("DB__HOST"),
+        Some(&"localhost".to_string())
+    );
+
+    let res = FlatConfig::builder()
+        .add_directory("tests/fixtures/layered")
+        .with_default_basename("base")
+        .build();
+
+    assert!(matches!(res, Err(ConfpilerError::MissingDefault { .. })));
+}
+
+#[test]
+fn inheriting_environments() {
+    let (config, _) = FlatConfig::builder()
+        .add_directory("tests/fixtures/inherited")
+        .with_environment("production-eu-canary")
+        .build()
+        .expect("Failed to construct config");
+
+    let expected = IndexMap::from([
+        ("DB__HOST".to_string(), "eu.prod.db.internal".to_string()),
+        ("DB__PORT".to_string(), "5432".to_string()),
+        ("LOG_LEVEL".to_string(), "debug".to_string()),
+        ("REGION".to_string(), "eu-west-1".to_string()),
+        ("REPLICAS".to_string(), "1".to_string()),
+    ]);
+
+    assert_eq!(config.items(), &expected);
+    assert_eq!(
+        config.layers(),
+        &[
+            "tests/fixtures/inherited/default".to_string(),
+            "tests/fixtures/inherited/production".to_string(),
+            "tests/fixtures/inherited/production-eu".to_string(),
+            "tests/fixtures/inherited/production-eu-canary".to_string(),
+        ]
+    );
+
+    // specifying the extension doesn't skip the parents
+    let (config, _) = FlatConfig::builder()
+        .add_directory("tests/fixtures/inherited")
+        .with_environment("production-eu-canary.yaml")
+        .build()
+        .expect("Failed to construct config");
+
+    assert_eq!(config.items(), &expected);
+    assert_eq!(
+        config.layers(),
+        &[
+            "tests/fixtures/inherited/default".to_string(),
+            "tests/fixtures/inherited/production".to_string(),
+            "tests/fixtures/inherited/production-eu".to_string(),
+            "tests/fixtures/inherited/production-eu-canary.yaml".to_string(),
+        ]
+    );
+
+    let res = FlatConfig::builder()
+        .add_directory("tests/fixtures/inherited_cycle")
+        .with_environment("production")
+        .build();
+
+    assert!(matches!(
+        res,
+        Err(ConfpilerError::EnvironmentCycle(cycle))
+            if cycle == vec!["production", "production-eu", "production"]
+    ));
+}
+
+#[test]
+fn requiring_environments() {
+    // the layered directory also contains a README.md and a .gitkeep, which
+    // aren't environments
+
+    // without requiring environments, typos are silently ignored
+    let (config, _) = FlatConfig::builder()
+        .add_directory("tests/fixtures/layered")
+        .with_environment("prodution")
+        .build()
+        .expect("Failed to construct config");
+
+    assert_eq!(
+        config.layers(),
+        &["tests/fixtures/layered/default".to_string()]
+    );
+
+    let res = FlatConfig::builder()
+        .add_directory("tests/fixtures/layered")
+        .with_environment("prodution")
+        .with_require_environments(true)
+        .build();
+
+    match res {
+        Err(err @ ConfpilerError::UnknownEnvironment { .. }) => {
+            assert_eq!(
+                err.to_string(),
+                "no config found for the environment \"prodution\" (did you mean \"production\"?), available environments: production, production-east, staging"
+            );
+        }
+        _ => panic!("unexpected result: {res:?}"),
+    }
+
+    FlatConfig::builder()
+        .add_directory("tests/fixtures/layered")
+        .with_environment("staging")
+        .with_require_environments(true)
+        .build()
+        .expect("Failed to construct config");
+}
@@ -1,10 +1,18 @@
 use std::path::{Path, PathBuf};
 
 use anyhow::{anyhow, bail, Context, Result};
-use clap::{Args, Parser, Subcommand};
-use confpiler::{error::ConfpilerError, FlatConfig, MergeWarning};
+use clap::{ArgGroup, Args, Parser, Subcommand};
+use confpiler::{
+    error::ConfpilerError, indexmap::IndexMap, ArrayStrategy, DocumentFormat, FlatConfig,
+    InvalidKeyPolicy, KeyFormat, MergeWarning, NewKeyPolicy, Schema, SourceFormat,
+    TypeChangePolicy, Unflattener,
+};
 use self_update::{backends::github, cargo_crate_version, get_target};
 
+/// The name used to refer to configuration read from stdin in warnings, errors,
+/// and explanations.
+const STDIN_ORIGIN: &str = "<stdin>";
+
 const EXAMPLES: &str = "
 Examples:
 compiling a single configuration:
@@ -32,6 +40,38 @@ compile into a configuration made up of default.json + production.json:
 getting the output as json:
 
     confpiler build mydir --env production --json
+
+
+checking that a configuration provides everything the app expects:
+
+    confpiler check mydir --env production --schema schema.yaml
+
+
+explaining where the value(s) for a key (or glob of keys) came from:
+
+    confpiler explain 'MYAPP__DB__*' global.yaml mydir --env production
+
+
+seeing what a compiled configuration looks like as a single (nested) toml file:
+
+    confpiler unflatten global.yaml mydir --env production --format toml
+
+
+seeing what would change if staging were configured like production:
+
+    confpiler diff global.yaml mydir --env production --against-env staging
+
+
+checking whether a configuration changed since it was last built (exits
+nonzero if it did):
+
+    confpiler build mydir --env production --json > saved.json
+    confpiler diff mydir --env production --against saved.json
+
+
+reading a layer from stdin (the position of '-' determines its precedence):
+
+    sops -d secrets.enc.yaml | confpiler build mydir --env production - --stdin-format yaml
 ";
 
 /// A configuration compiler and exporter use --help (long help) for more
@@ -42,13 +82,14 @@ getting the output as json:
 /// in a more human-manageable form by then exporting those variables in a way
 /// your standard "twelve-factor" app would consume.
 ///
-/// JSON, TOML, YAML, and INI are supported formats, and, while not recommended,
-/// you can mix and match.
+/// JSON, TOML, YAML, INI, and dotenv are supported formats, and, while not
+/// recommended, you can mix and match. Keys from dotenv files are used as-is.
 ///
-/// This DOES NOT support array values of complex types (like other arrays or
-/// dictionaries), as those do not translate well to environment variables
-/// without some additional encoding. Arrays of simple types are joined by a
-/// separator into a single string.
+/// By default, this DOES NOT support array values of complex types (like other
+/// arrays or dictionaries), as those do not translate well to environment
+/// variables without some additional encoding (see --json-arrays). Arrays of
+/// simple types are joined by a separator into a single string, or,
+/// optionally, given one key per element.
 ///
 #[derive(Parser)]
 #[clap(
@@ -69,6 +110,12 @@ pub enum TopLevel {
     Build(BuildArgs),
     /// Checks if a configuration would be valid and exits nonzero if not
     Check(CheckArgs),
+    /// Shows every config that set the value of a key, in load order
+    Explain(ExplainArgs),
+    /// Compares a configuration to another and exits nonzero if they differ
+    Diff(DiffArgs),
+    /// Compiles a configuration, then rebuilds it as a nested document
+    Unflatten(UnflattenArgs),
     /// Attempt to update confpiler
     Update(UpdateArgs),
 }
@@ -82,7 +129,10 @@ pub struct BuildArgs {
     #[clap(short, long)]
     pub json: bool,
 
-    /// Disable sorting for .env-style output (json is always unsorted)
+    /// Disable sorting for .env-style output (json is never sorted)
+    ///
+    /// Unsorted output lists keys in the order they first appear in the
+    /// configuration(s).
     #[clap(short = 'N', long = "no-sort")]
     pub no_sort: bool,
 
@@ -101,6 +151,117 @@ pub struct CheckArgs {
 }
 
 #[derive(Args)]
+pub struct ExplainArgs {
+    /// The key to explain
+    ///
+    /// This may be a glob, where `*` matches any sequence of characters and
+    /// `?` matches any single character. Globs should be quoted to avoid
+    /// expansion by the shell.
+    pub key: String,
+
+    #[clap(flatten)]
+    pub common: CommonConfigArgs,
+}
+
+impl ExplainArgs {
+    /// Determine if the given key is matched by the requested key/glob.
+    pub fn matches(&self, key: &str) -> bool {
+        glob_matches(self.key.as_bytes(), key.as_bytes())
+    }
+}
+
+#[derive(Args)]
+#[clap(group(ArgGroup::new("baseline").required(true).args(&["against-env", "against"])))]
+pub struct DiffArgs {
+    #[clap(flatten)]
+    pub common: CommonConfigArgs,
+
+    /// Compare against the same PATH(s) compiled for this environment
+    ///
+    /// Like --env, this may be specified multiple times, and replaces every
+    /// environment specified via --env.
+    #[clap(long)]
+    pub against_env: Vec<String>,
+
+    /// Compare against the previously saved output of `build --json`
+    #[clap(long, parse(from_os_str))]
+    pub against: Option<PathBuf>,
+
+    /// Output the differences as json
+    #[clap(short, long)]
+    pub json: bool,
+}
+
+impl DiffArgs {
+    /// Get the configuration being compared against.
+    pub fn get_baseline(&self) -> Result<FlatConfig> {
+        if !self.against_env.is_empty() {
+            if self.common.path.iter().any(|p| p == Path::new("-")) {
+                bail!("Reading a config from stdin cannot be combined with --against-env");
+            }
+
+            let mut common = self.common.clone();
+            common.environment = self.against_env.clone();
+
+            let (conf, _) = common.get_config()?;
+            return Ok(conf);
+        }
+
+        // clap guarantees one of the two is specified
+        let path = self
+            .against
+            .as_ref()
+            .ok_or_else(|| anyhow!("Must specify --against-env or --against"))?;
+        let path_str = path
+            .to_str()
+            .ok_or_else(|| anyhow!("Path does not contain valid characters"))?;
+        let contents = std::fs::read_to_string(path)
+            .with_context(|| format!("Failed to read {}", path.display()))?;
+
+        // the saved keys and values are already in their final form, so they
+        // must be loaded as-is instead of being flattened again (which would
+        // split keys like `foo.bar` into tables)
+        let items: IndexMap<String, String> = serde_json::from_str(&contents)
+            .with_context(|| format!("'{}' is not valid saved output", path.display()))?;
+
+        Ok(FlatConfig::from_items(path_str, items))
+    }
+}
+
+#[derive(Args)]
+pub struct UnflattenArgs {
+    #[clap(flatten)]
+    pub common: CommonConfigArgs,
+
+    /// The format of the nested document
+    #[clap(short, long, default_value = "yaml", possible_values = DocumentFormat::VARIANTS)]
+    pub format: DocumentFormat,
+}
+
+impl UnflattenArgs {
+    /// Make an [Unflattener] that reverses the flattening performed by the
+    /// common config args.
+    pub fn unflattener(&self) -> Unflattener {
+        let mut unflattener = Unflattener::default();
+        let key_format = &self.common.key_format;
+
+        unflattener.with_separator(key_format.separator().unwrap_or(&self.common.separator));
+
+        // there's no way to know what the original case was for formats that
+        // convert to uppercase, so we assume lowercase
+        if !matches!(key_format, KeyFormat::Upper | KeyFormat::ScreamingSnake) {
+            unflattener.with_key_format(KeyFormat::Preserve);
+        }
+
+        if let Some(ref prefix) = self.common.prefix {
+            unflattener.with_prefix(&key_format.format(prefix));
+        }
+
+        unflattener
+    }
+}
+
+#[derive(Args, Clone)]
 pub struct CommonConfigArgs {
     /// Path(s) to load configuration from
     ///
@@ -110,8 +271,15 @@ pub struct CommonConfigArgs {
     /// 1) If PATH is a an explicit file, this file is always loaded.
     ///
     /// 2) If PATH is a directory, the DEFAULT is loaded followed by the config
-    /// corresponding to the specified ENVIRONMENT, if any. If no environment
-    /// is set, ONLY the default is loaded.
+    ///    corresponding to each specified ENVIRONMENT, if any, in the order
+    ///    they were specified. If no environment is set, ONLY the default is
+    ///    loaded. If the directory contains an "environments" file declaring a
+    ///    parent for an ENVIRONMENT
+    ///    (like `production-eu: { parent: production }`), the configs for its
+    ///    ancestors are loaded before it.
+    ///
+    /// 3) If PATH is "-", a config in the STDIN_FORMAT is read from stdin. This
+    ///    may only be specified once.
     ///
     /// This means that if specifying multiple directories, each directory is
     /// processed completely before moving on to the next directory
@@ -123,20 +291,44 @@ pub struct CommonConfigArgs {
 
     /// The environment to compile (has no effect unless specifying a directory).
     ///
-    /// If no corresponding file exists, it is ignored.
+    /// This may be specified multiple times to stack overlays (like
+    /// `--env production --env feature-x --env local`), with later
+    /// environments taking precedence. If no corresponding file exists, it is
+    /// ignored, unless --require-env or --strict is specified. Parents declared
+    /// in a directory's "environments" file are loaded first.
     #[clap(short, long = "env")]
-    pub environment: Option<String>,
+    pub environment: Vec<String>,
+
+    /// Error if an ENVIRONMENT has no corresponding file in any directory
+    ///
+    /// This is implied by --strict.
+    #[clap(long)]
+    pub require_env: bool,
 
     /// Basename of file(s) to consider default when operating on directories
     #[clap(short, long, default_value = "default")]
     pub default: String,
 
+    /// The format of the config read from stdin when a PATH is "-"
+    #[clap(long, possible_values = SourceFormat::VARIANTS)]
+    pub stdin_format: Option<SourceFormat>,
+
     /// A prefix to prepend to all generated keys.
     ///
-    /// This value will always be converted to uppercase.
+    /// This value is formatted like any other key (converted to uppercase, by
+    /// default).
     #[clap(short, long)]
     pub prefix: Option<String>,
 
+    /// How to format the components of generated keys
+    ///
+    /// "upper" converts to uppercase, "preserve" leaves keys as written,
+    /// "lower" converts to lowercase, "screaming-snake" converts camelCase
+    /// and kebab-case keys to SCREAMING_SNAKE_CASE, and "dotted" converts to
+    /// lowercase and joins components with "." (ignoring the SEPARATOR).
+    #[clap(long, default_value = "upper", possible_values = KeyFormat::VARIANTS)]
+    pub key_format: KeyFormat,
+
     /// The separator to use when flattening keys from config files
     #[clap(short, long, default_value = "__")]
     pub separator: String,
@@ -145,6 +337,108 @@ pub struct CommonConfigArgs {
     #[clap(short = 'a', long, default_value = ",")]
     pub array_separator: String,
 
+    /// How to flatten arrays
+    ///
+    /// "joined" joins the values of an array with the ARRAY_SEPARATOR,
+    /// "indexed" gives every element its own key (FOO__0, FOO__1, etc.), and
+    /// "both" does both.
+    #[clap(long, default_value = "joined", possible_values = ArrayStrategy::VARIANTS)]
+    pub array_strategy: ArrayStrategy,
+
+    /// The separator between an array key and the index of an element
+    ///
+    /// Defaults to the SEPARATOR.
+    #[clap(long)]
+    pub index_separator: Option<String>,
+
+    /// Generate an additional key containing the length of every array
+    ///
+    /// The key is made of the array key, the INDEX_SEPARATOR, and this suffix
+    /// (FOO__LEN, for example).
+    #[clap(long)]
+    pub array_length_suffix: Option<String>,
+
+    /// Encode arrays containing arrays or maps as compact JSON strings
+    ///
+    /// Without this, such arrays result in an error.
+    #[clap(long)]
+    pub json_arrays: bool,
+
+    /// Encode the value at this (flattened) key as a compact JSON string
+    ///
+    /// The key should be specified as it would otherwise appear in the output,
+    /// including any prefix, and may refer to a map or an array. This may be
+    /// specified multiple times.
+    #[clap(long = "json-key", multiple_occurrences = true)]
+    pub json_keys: Vec<String>,
+
+    /// How to handle keys introduced by any config other than the first
+    ///
+    /// Setting this to "warn" or "deny" is useful for catching typos in
+    /// environment-specific configs that are only meant to override values.
+    /// Note that this applies to every config other than the first, including
+    /// the DEFAULT of every directory after the first PATH.
+    #[clap(long, default_value = "allow", possible_values = NewKeyPolicy::VARIANTS)]
+    pub new_keys: NewKeyPolicy,
+
+    /// How to handle configs that change the type of a value
+    ///
+    /// Replacing a value with one of a different type (like a number with a
+    /// string) generates a warning by default. Replacing an integer with a
+    /// float (or vice versa) is never considered a type change.
+    #[clap(long, default_value = "warn", possible_values = TypeChangePolicy::VARIANTS)]
+    pub type_changes: TypeChangePolicy,
+
+    /// How to handle keys that are not valid environment variable names
+    ///
+    /// Valid names consist of letters, digits, and underscores, and do not
+    /// start with a digit. "sanitize" replaces illegal characters with
+    /// underscores (and prefixes keys starting with a digit with one), while
+    /// "error" refuses to produce output containing invalid names.
+    #[clap(long, default_value = "allow", possible_values = InvalidKeyPolicy::VARIANTS)]
+    pub invalid_keys: InvalidKeyPolicy,
+
+    /// Resolve ${KEY} references in values
+    ///
+    /// A value may refer to the final value of any other key (as it would
+    /// appear in the output) with ${KEY}. A literal "${" can be written as
+    /// "$${". Without this, such references are left as-is.
+    #[clap(long)]
+    pub interpolate: bool,
+
+    /// Resolve ${env:VAR} references in values using the current environment
+    ///
+    /// ${env:VAR:-default} provides a default for when VAR is unset or empty,
+    /// and ${env:VAR:?message} fails with the given message. Otherwise, unset
+    /// variables result in an error. Without this, such references are left
+    /// as-is.
+    #[clap(long)]
+    pub interpolate_env: bool,
+
+    /// Treat null values as deleting keys set by previous configs
+    ///
+    /// A null replacing a map or array deletes every key generated from it.
+    /// Nulls that do not delete anything generate warnings.
+    #[clap(long)]
+    pub null_deletes: bool,
+
+    /// A placeholder value marking keys that must be set by a later config
+    ///
+    /// If any key still has exactly this value (like "CHANGEME") once every
+    /// config is merged, this fails, listing every such key.
+    #[clap(long)]
+    pub required_marker: Option<String>,
+
+    /// Validate the compiled configuration against this schema
+    ///
+    /// The schema maps final keys (as they would appear in the output) to
+    /// their expected type ("string", "int", "float", "bool", "url",
+    /// "duration", "enum" with "values", or "regex" with a "pattern"), whether
+    /// they are "required", and, for numbers, an inclusive "min" and "max".
+    /// Every violation is reported.
+    #[clap(long)]
+    pub schema: Option<String>,
+
     /// Error on warnings
     #[clap(long)]
     pub strict: bool,
@@ -153,47 +447,89 @@ pub struct CommonConfigArgs {
 impl CommonConfigArgs {
     pub fn try_make_config(&self) -> Result<(FlatConfig, Vec<MergeWarning>)> {
         let mut builder = FlatConfig::builder();
+        builder.with_key_format(self.key_format.clone());
         builder.with_separator(&self.separator);
         builder.with_array_separator(&self.array_separator);
+        builder.with_array_strategy(self.array_strategy);
+        builder.with_json_arrays(self.json_arrays);
+
+        for key in self.json_keys.iter() {
+            builder.with_json_key(key);
+        }
+        builder.with_new_key_policy(self.new_keys);
+        builder.with_type_change_policy(self.type_changes);
+        builder.with_invalid_key_policy(self.invalid_keys);
+        builder.with_interpolation(self.interpolate);
+        builder.with_env_interpolation(self.interpolate_env);
+        builder.with_null_deletes(self.null_deletes);
+        builder.with_default_basename(&self.default);
+
+        for environment in self.environment.iter() {
+            builder.with_environment(environment);
+        }
+        builder.with_require_environments(self.require_env || self.strict);
 
         if let Some(ref prefix) = self.prefix {
             builder.with_prefix(prefix);
         }
 
+        if let Some(ref separator) = self.index_separator {
+            builder.with_index_separator(separator);
+        }
+
+        if let Some(ref suffix) = self.array_length_suffix {
+            builder.with_array_length_suffix(suffix);
+        }
+
+        if let Some(ref marker) = self.required_marker {
+            builder.with_required_marker(marker);
+        }
+
+        if let Some(ref schema) = self.schema {
+            builder.with_schema(
+                Schema::from_file(schema)
+                    .with_context(|| format!("Failed to load schema '{schema}'"))?,
+            );
+        }
+
+        let mut stdin: Option<String> = None;
+
         for p in self.path.iter() {
             let path = p.as_path();
 
+            if path == Path::new("-") {
+                let format = self.stdin_format.ok_or_else(|| {
+                    anyhow!("Reading a config from stdin requires --stdin-format")
+                })?;
+
+                // stdin can only be read once, but specifying it twice is
+                // reported as a duplicate config by the builder
+                let content = match stdin {
+                    Some(ref content) => content,
+                    None => stdin.insert(
+                        std::io::read_to_string(std::io::stdin())
+                            .context("Failed to read config from stdin")?,
+                    ),
+                };
+
+                builder.add_config_str(STDIN_ORIGIN, content, format);
+                continue;
+            }
+
             if !path.exists() {
                 bail!("Path '{}' does not exist", path.display());
             }
 
-            // we have to add two sources: the "default" and the "env", if it
-            // exists
+            let path_str = path
+                .to_str()
+                .ok_or_else(|| anyhow!("Path does not contain valid characters"))?;
+
+            // directories are expanded into the "default" and the "env", if it
+            // exists, by the builder
             if path.is_dir() {
-                let def = path.join(&self.default);
-                let def_str = def
-                    .to_str()
-                    .ok_or_else(|| anyhow!("Path does not contain valid characters"))?;
-                builder.add_config(def_str);
-
-                if let Some(ref environment) = self.environment {
-                    let env = path.join(environment);
-
-                    // we allow either specifying the full filename or just the
-                    // stem as env
-                    if env.exists() || check_stem_exists(path, environment)? {
-                        let env_str = env
-                            .to_str()
-                            .ok_or_else(|| anyhow!("Path does not contain valid characters"))?;
-
-                        builder.add_config(env_str);
-                    }
-                }
+                builder.add_directory(path_str);
             } else {
-                builder.add_config(
-                    path.to_str()
-                        .ok_or_else(|| anyhow!("Path does not contain valid characters"))?,
-                );
+                builder.add_config(path_str);
             }
         }
 
@@ -217,24 +553,18 @@ impl CommonConfigArgs {
     }
 }
 
-fn check_stem_exists(path: &Path, desired: &str) -> Result<bool> {
-    if path.is_dir() {
-        Ok(path
-            .read_dir()
-            .with_context(|| format!("Failed to read {}", path.display()))?
-            .any(|entry| {
-                if let Ok(entry) = entry {
-                    let path = entry.path();
-                    if path.is_file() {
-                        if let Some(stem) = path.file_stem() {
-                            return stem.to_str() == Some(desired);
-                        }
-                    }
-                }
-                false
-            }))
-    } else {
-        Ok(false)
+fn glob_matches(pattern: &[u8], candidate: &[u8]) -> bool {
+    match (pattern.first(), candidate.first()) {
+        (None, None) => true,
+        // a '*' either matches nothing, or consumes one character and tries
+        // again
+        (Some(b'*'), _) => {
+            glob_matches(&pattern[1..], candidate)
+                || (!candidate.is_empty() && glob_matches(pattern, &candidate[1..]))
+        }
+        (Some(b'?'), Some(_)) => glob_matches(&pattern[1..], &candidate[1..]),
+        (Some(p), Some(c)) if p == c => glob_matches(&pattern[1..], &candidate[1..]),
+        _ => false,
     }
 }
 
@@ -1,7 +1,7 @@
-use anyhow::Result;
+use anyhow::{bail, Result};
 use clap::Parser;
-use cli::{Cli, TopLevel};
-use confpiler::MergeWarning;
+use cli::{Cli, DiffArgs, TopLevel};
+use confpiler::{KeyDiff, MergeWarning, Provenance};
 use snailquote::escape;
 
 mod cli;
@@ -13,6 +13,8 @@ fn main() -> Result<()> {
             let (conf, _) = build_args.common.get_config()?;
 
             if build_args.json {
+                // the items are serialized in insertion order, which keeps the
+                // output in the same order as the configs
                 println!("{}", serde_json::to_string(conf.items())?);
             } else {
                 // I guess we could import itertools for the sorting, but eh
@@ -49,7 +51,14 @@ fn main() -> Result<()> {
         }
         TopLevel::Check(check_args) => {
             println!("Checking configuration...");
-            let (_, warnings) = check_args.common.get_config()?;
+            let (conf, warnings) = check_args.common.get_config()?;
+
+            // the order configs were actually loaded in, which is useful for
+            // verifying how directories and environments were resolved
+            println!("Layers:");
+            for layer in conf.layers() {
+                println!("    {layer}");
+            }
 
             if !warnings.is_empty() {
                 // just print the warnings here, since we handled the strict
@@ -60,6 +69,53 @@ fn main() -> Result<()> {
 
             println!("\nok")
         }
+        TopLevel::Explain(explain_args) => {
+            let (conf, _) = explain_args.common.get_config()?;
+
+            // deleted keys are no longer part of the config, but explaining
+            // why they're missing is still useful
+            let mut keys = conf
+                .items()
+                .keys()
+                .map(|k| k.as_str())
+                .chain(conf.deleted_keys())
+                .filter(|k| explain_args.matches(k))
+                .collect::<Vec<_>>();
+
+            if keys.is_empty() {
+                bail!("No keys matching '{}'", explain_args.key);
+            }
+
+            keys.sort();
+
+            let explanations = keys
+                .into_iter()
+                .filter_map(|k| conf.provenance(k).map(|p| explain_formatter(k, p)))
+                .collect::<Vec<_>>();
+
+            println!("{}", explanations.join("\n\n"));
+        }
+        TopLevel::Diff(diff_args) => {
+            // like diff(1), differences are indicated by exiting with 1, so
+            // errors have to exit with 2 instead of the usual 1
+            match diff(diff_args) {
+                Ok(false) => {}
+                Ok(true) => std::process::exit(1),
+                Err(err) => {
+                    eprintln!("Error: {err:?}");
+                    std::process::exit(2);
+                }
+            }
+        }
+        TopLevel::Unflatten(unflatten_args) => {
+            let (conf, _) = unflatten_args.common.get_config()?;
+
+            let document = unflatten_args
+                .unflattener()
+                .render(conf.items(), unflatten_args.format)?;
+
+            print!("{document}");
+        }
         TopLevel::Update(update_args) => {
             update_args.update()?;
         }
@@ -68,6 +124,27 @@ fn main() -> Result<()> {
     Ok(())
 }
 
+/// Print the differences between the configs, returning `true` if there were
+/// any.
+fn diff(diff_args: &DiffArgs) -> Result<bool> {
+    let baseline = diff_args.get_baseline()?;
+    let (conf, _) = diff_args.common.get_config()?;
+
+    let mut diff = baseline.diff(&conf);
+    diff.sort_by(|a, b| a.key().cmp(b.key()));
+
+    if diff_args.json {
+        let changes = diff.iter().map(diff_json).collect::<Vec<_>>();
+        println!("{}", serde_json::to_string(&changes)?);
+    } else {
+        for change in diff.iter() {
+            println!("{}", diff_formatter(change));
+        }
+    }
+
+    Ok(!diff.is_empty())
+}
+
 // so doing this sort here is a little weird, but we already have sorted output
 // if it's an error
 //
@@ -80,3 +157,84 @@ fn warnings_formatter(warnings: &[MergeWarning]) -> String {
     out.sort();
     out.join("\n")
 }
+
+fn explain_formatter(key: &str, provenance: &Provenance) -> String {
+    let last = provenance.assignments().len() - 1;
+    let mut out = vec![key.to_string()];
+
+    for (i, assignment) in provenance.assignments().iter().enumerate() {
+        let marker = if i == last { " (final)" } else { "" };
+        let value = if assignment.is_deletion() {
+            "(deleted)".to_string()
+        } else {
+            format!("'{}'", assignment.value())
+        };
+        out.push(format!("    {}: {}{}", assignment.origin(), value, marker));
+    }
+
+    out.join("\n")
+}
+
+fn diff_formatter(change: &KeyDiff) -> String {
+    let origin = |origin: &Option<String>| {
+        origin
+            .as_ref()
+            .map(|o| format!(" ({o})"))
+            .unwrap_or_default()
+    };
+
+    match change {
+        KeyDiff::Added {
+            key,
+            value,
+            origin: o,
+        } => format!("+ {key}: '{value}'{}", origin(o)),
+        KeyDiff::Removed {
+            key,
+            value,
+            origin: o,
+        } => format!("- {key}: '{value}'{}", origin(o)),
+        KeyDiff::Changed {
+            key,
+            old_value,
+            new_value,
+            old_origin,
+            new_origin,
+        } => format!(
+            "~ {key}: '{old_value}'{} -> '{new_value}'{}",
+            origin(old_origin),
+            origin(new_origin)
+        ),
+    }
+}
+
+fn diff_json(change: &KeyDiff) -> serde_json::Value {
+    let (kind, old_value, new_value, old_origin, new_origin) = match change {
+        KeyDiff::Added { value, origin, .. } => ("added", None, Some(value), None, origin.as_ref()),
+        KeyDiff::Removed { value, origin, .. } => {
+            ("removed", Some(value), None, origin.as_ref(), None)
+        }
+        KeyDiff::Changed {
+            old_value,
+            new_value,
+            old_origin,
+            new_origin,
+            ..
+        } => (
+            "changed",
+            Some(old_value),
+            Some(new_value),
+            old_origin.as_ref(),
+            new_origin.as_ref(),
+        ),
+    };
+
+    serde_json::json!({
+        "key": change.key(),
+        "change": kind,
+        "old_value": old_value,
+        "new_value": new_value,
+        "old_origin": old_origin,
+        "new_origin": new_origin,
+    })
+}
@@ -6,7 +6,8 @@ use std::fs;
 /// Retrieve golden output from file
 fn golden(directory: &str, file: &str) -> String {
     let desired = format!("tests/golden/{directory}/{file}");
-    fs::read_to_string(&desired).expect(&format!("could not open golden output file: {}", &desired))
+    fs::read_to_string(&desired)
+        .unwrap_or_else(|_| panic!("could not open golden output file: {}", &desired))
 }
 
 fn parse_stdout_stderr(input: &str) -> (String, String) {
@@ -22,6 +23,12 @@ fn run(op: &str, args: &[&str]) -> Assert {
     cmd.arg(op).args(args).assert()
 }
 
+/// run the command, with the given input on stdin
+fn run_with_stdin(op: &str, args: &[&str], stdin: &str) -> Assert {
+    let mut cmd = Command::cargo_bin("confpiler").expect("could not get desired binary");
+    cmd.arg(op).args(args).write_stdin(stdin).assert()
+}
+
 #[test]
 fn raw_output() {
     let expected = golden("raw", "build_output.txt");
@@ -39,3 +46,297 @@ fn raw_output() {
 
     result.success().stdout(stdout).stderr(stderr);
 }
+
+#[test]
+fn explain_single_key() {
+    let expected = golden("explain", "single_output.txt");
+    let (stdout, stderr) = parse_stdout_stderr(&expected);
+    let result = run(
+        "explain",
+        &[
+            "SMOKE",
+            "tests/fixtures/global_default.yaml",
+            "tests/fixtures/conf_dir",
+            "--env",
+            "production",
+        ],
+    );
+
+    result.success().stdout(stdout).stderr(stderr);
+}
+
+#[test]
+fn explain_glob() {
+    let expected = golden("explain", "glob_output.txt");
+    let (stdout, stderr) = parse_stdout_stderr(&expected);
+    let result = run(
+        "explain",
+        &[
+            "MYKEY__*",
+            "tests/fixtures/global_default.yaml",
+            "tests/fixtures/conf_dir",
+            "--env",
+            "staging",
+        ],
+    );
+
+    result.success().stdout(stdout).stderr(stderr);
+}
+
+#[test]
+fn explain_deleted_key() {
+    let expected = golden("explain", "deleted_output.txt");
+    let (stdout, stderr) = parse_stdout_stderr(&expected);
+    let result = run(
+        "explain",
+        &[
+            "MYKEY__BAR",
+            "tests/fixtures/conf_dir",
+            "--env",
+            "trimmed",
+            "--null-deletes",
+        ],
+    );
+
+    result.success().stdout(stdout).stderr(stderr);
+}
+
+#[test]
+fn explain_no_matching_keys() {
+    let expected = golden("explain", "no_match_output.txt");
+    let (stdout, stderr) = parse_stdout_stderr(&expected);
+    let result = run("explain", &["NOPE", "tests/fixtures/conf_dir"]);
+
+    result.failure().stdout(stdout).stderr(stderr);
+}
+
+#[test]
+fn unsorted_output_preserves_config_order() {
+    let expected = golden("ordered", "build_output.txt");
+    let result = run(
+        "build",
+        &[
+            "tests/fixtures/global_default.yaml",
+            "tests/fixtures/conf_dir",
+            "--env",
+            "production",
+            "--no-sort",
+        ],
+    );
+
+    result.success().stdout(expected);
+}
+
+#[test]
+fn json_output_preserves_config_order() {
+    let expected = golden("ordered", "build_output.json");
+    let result = run(
+        "build",
+        &[
+            "tests/fixtures/global_default.yaml",
+            "tests/fixtures/conf_dir",
+            "--env",
+            "production",
+            "--json",
+        ],
+    );
+
+    result.success().stdout(expected);
+}
+
+#[test]
+fn unflatten_yaml() {
+    let expected = golden("unflatten", "yaml_output.txt");
+    let result = run(
+        "unflatten",
+        &[
+            "tests/fixtures/global_default.yaml",
+            "tests/fixtures/conf_dir",
+            "--env",
+            "production",
+            "--prefix",
+            "myApp",
+        ],
+    );
+
+    result.success().stdout(expected);
+}
+
+#[test]
+fn unflatten_toml() {
+    let expected = golden("unflatten", "toml_output.txt");
+    let result = run(
+        "unflatten",
+        &[
+            "tests/fixtures/global_default.yaml",
+            "tests/fixtures/conf_dir",
+            "--env",
+            "production",
+            "--prefix",
+            "myApp",
+            "--format",
+            "toml",
+        ],
+    );
+
+    result.success().stdout(expected);
+}
+
+#[test]
+fn build_from_stdin() {
+    let expected = golden("stdin", "build_output.txt");
+    let result = run_with_stdin(
+        "build",
+        &[
+            "tests/fixtures/global_default.yaml",
+            "-",
+            "--stdin-format",
+            "toml",
+        ],
+        "smoke = \"stdin\"\n[db]\nhost = \"secret.host\"\n",
+    );
+
+    result.success().stdout(expected);
+}
+
+#[test]
+fn explain_from_stdin() {
+    let expected = golden("stdin", "explain_output.txt");
+    let result = run_with_stdin(
+        "explain",
+        &[
+            "SMOKE",
+            "tests/fixtures/global_default.yaml",
+            "-",
+            "--stdin-format",
+            "yaml",
+        ],
+        "smoke: x\n",
+    );
+
+    result.success().stdout(expected);
+}
+
+#[test]
+fn stdin_requires_format() {
+    let expected = golden("stdin", "missing_format_output.txt");
+    let (stdout, stderr) = parse_stdout_stderr(&expected);
+    let result = run_with_stdin("build", &["-"], "x\n");
+
+    result.failure().stdout(stdout).stderr(stderr);
+}
+
+#[test]
+fn diff_environments() {
+    let expected = golden("diff", "envs_output.txt");
+    let result = run(
+        "diff",
+        &[
+            "tests/fixtures/global_default.yaml",
+            "tests/fixtures/conf_dir",
+            "--env",
+            "typo",
+            "--against-env",
+            "production",
+        ],
+    );
+
+    result.code(1).stdout(expected);
+}
+
+#[test]
+fn diff_saved_output() {
+    let expected = golden("diff", "saved_output.json");
+    let result = run(
+        "diff",
+        &[
+            "tests/fixtures/conf_dir",
+            "--env",
+            "staging",
+            "--against",
+            "tests/fixtures/saved_staging.json",
+            "--json",
+        ],
+    );
+
+    result.code(1).stdout(expected);
+}
+
+#[test]
+fn diff_no_differences() {
+    let result = run(
+        "diff",
+        &[
+            "tests/fixtures/global_default.yaml",
+            "tests/fixtures/conf_dir",
+            "--env",
+            "staging",
+            "--against",
+            "tests/fixtures/saved_staging.json",
+        ],
+    );
+
+    result.success().stdout("");
+}
+
+#[test]
+fn diff_saved_build() {
+    // saved keys must be compared as-is, even when they contain dots
+    let args = [
+        "tests/fixtures/conf_dir",
+        "--env",
+        "staging",
+        "--key-format",
+        "dotted",
+    ];
+
+    let saved = run("build", &[&args[..], &["--json"]].concat())
+        .success()
+        .get_output()
+        .stdout
+        .clone();
+
+    let path = std::env::temp_dir().join(format!("confpiler-saved-{}.json", std::process::id()));
+    fs::write(&path, saved).expect("could not write saved output");
+
+    let result = run(
+        "diff",
+        &[
+            &args[..],
+            &["--against", path.to_str().expect("invalid path")],
+        ]
+        .concat(),
+    );
+
+    result.success().stdout("");
+
+    fs::remove_file(&path).expect("could not remove saved output");
+}
+
+#[test]
+fn diff_errors() {
+    // 1 means there were differences, so errors exit with 2
+    let result = run(
+        "diff",
+        &[
+            "tests/fixtures/conf_dir",
+            "--env",
+            "staging",
+            "--against",
+            "tests/fixtures/nonexistent.json",
+        ],
+    );
+
+    result.code(2).stdout("");
+
+    let result = run(
+        "diff",
+        &[
+            "tests/fixtures/nonexistent.yaml",
+            "--against",
+            "tests/fixtures/saved_staging.json",
+        ],
+    );
+
+    result.code(2).stdout("");
+}
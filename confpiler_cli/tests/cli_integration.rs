@@ -202,6 +202,27 @@ integration_test! {
     succeeds,
 }
 
+integration_test! {
+    inherited_env,
+    ["tests/fixtures/inherited_dir"],
+    ["--env", "production-eu-canary"],
+    succeeds,
+}
+
+integration_test! {
+    stacked_envs,
+    ["tests/fixtures/inherited_dir"],
+    ["--env", "production", "--env", "feature-x", "--env", "local"],
+    succeeds,
+}
+
+integration_test! {
+    inherited_env_cycle,
+    ["tests/fixtures/inherited_dir"],
+    ["--env", "broken"],
+    fails,
+}
+
 integration_test! {
     customized,
     [
@@ -245,5 +266,168 @@ integration_test! {
         "tests/fixtures/conf_dir",
     ],
     ["--env", "missing"],
+    fails_strict,
+}
+
+integration_test! {
+    unknown_env,
+    [
+        "tests/fixtures/conf_dir",
+    ],
+    ["--env", "prodution", "--require-env"],
+    fails,
+}
+
+integration_test! {
+    new_keys_warn,
+    [
+        "tests/fixtures/conf_dir",
+    ],
+    ["--env", "typo", "--new-keys", "warn"],
+    fails_strict,
+}
+
+integration_test! {
+    new_keys_deny,
+    [
+        "tests/fixtures/conf_dir",
+    ],
+    ["--env", "typo", "--new-keys", "deny"],
+    fails,
+}
+
+integration_test! {
+    indexed_arrays,
+    [
+        "tests/fixtures/conf_dir",
+    ],
+    ["--env", "production", "--array-strategy", "both", "--array-length-suffix", "LEN"],
+    fails_strict,
+}
+
+integration_test! {
+    type_changes_allow,
+    [
+        "tests/fixtures/conf_dir",
+    ],
+    [
+        "--env",
+        "production",
+        "--array-strategy",
+        "both",
+        "--array-length-suffix",
+        "LEN",
+        "--type-changes",
+        "allow",
+    ],
+    succeeds,
+}
+
+integration_test! {
+    json_values,
+    ["tests/fixtures/complex_values.yaml"],
+    ["--json-arrays", "--json-key", "DB__REPLICAS"],
+    succeeds,
+}
+
+integration_test! {
+    unsupported_array,
+    ["tests/fixtures/complex_values.yaml"],
+    fails,
+}
+
+integration_test! {
+    key_format,
+    ["tests/fixtures/camel_case.yaml"],
+    ["--key-format", "screaming-snake", "--prefix", "myApp"],
+    succeeds,
+}
+
+integration_test! {
+    invalid_keys_sanitize,
+    ["tests/fixtures/invalid_names.yaml"],
+    ["--invalid-keys", "sanitize"],
+    succeeds,
+}
+
+integration_test! {
+    invalid_keys_error,
+    ["tests/fixtures/invalid_names.yaml"],
+    ["--invalid-keys", "error"],
+    fails,
+}
+
+integration_test! {
+    interpolation,
+    ["tests/fixtures/references.yaml"],
+    ["--interpolate"],
+    succeeds,
+}
+
+integration_test! {
+    interpolation_disabled,
+    ["tests/fixtures/references.yaml"],
+    succeeds,
+}
+
+integration_test! {
+    env_interpolation,
+    ["tests/fixtures/env_references.yaml"],
+    ["--interpolate", "--interpolate-env"],
+    succeeds,
+}
+
+integration_test! {
+    env_interpolation_required,
+    ["tests/fixtures/env_references_required.yaml"],
+    ["--interpolate-env"],
+    fails,
+}
+
+integration_test! {
+    null_deletes,
+    [
+        "tests/fixtures/conf_dir",
+    ],
+    ["--env", "trimmed", "--null-deletes"],
+    fails_strict,
+}
+
+integration_test! {
+    dotenv,
+    [
+        "tests/fixtures/conf_dir",
+        "tests/fixtures/legacy.env",
+    ],
+    succeeds,
+}
+
+integration_test! {
+    schema,
+    [
+        "tests/fixtures/global_default.yaml",
+        "tests/fixtures/conf_dir",
+    ],
+    ["--env", "production", "--schema", "tests/fixtures/schema.yaml"],
     succeeds,
 }
+
+integration_test! {
+    schema_violations,
+    [
+        "tests/fixtures/global_default.yaml",
+        "tests/fixtures/conf_dir",
+    ],
+    ["--env", "staging", "--schema", "tests/fixtures/schema.yaml"],
+    fails,
+}
+
+integration_test! {
+    required_marker,
+    [
+        "tests/fixtures/placeholders.yaml",
+        "tests/fixtures/conf_dir",
+    ],
+    ["--env", "production", "--required-marker", "CHANGEME"],
+    fails,
+}